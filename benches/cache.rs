@@ -0,0 +1,65 @@
+// Times a scripted game with the cache of decoded instructions turned off and then on, to show
+// what the cache saves. See common/mod.rs for choosing the story and script.
+//
+//     cargo bench --bench cache
+
+extern crate rustzork;
+
+mod common;
+
+use rustzork::{Header, Machine, Memory, ZIO};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 20;
+
+fn run(story: &[u8], commands: &[String], cache: bool) -> (Duration, u64) {
+    let memory = Memory::new(story);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    machine.seed(0);
+    machine.cache_instructions(cache);
+    *machine.io() = ZIO::headless(commands);
+    let start = Instant::now();
+    machine.step(None);
+    (start.elapsed(), machine.instruction_count())
+}
+
+// The median time of a number of runs, and the instructions each run took.
+fn measure(story: &[u8], commands: &[String], cache: bool) -> (f64, u64) {
+    run(story, commands, cache);
+    let mut times = Vec::new();
+    let mut instructions = 0;
+    for _ in 0..RUNS {
+        let (time, count) = run(story, commands, cache);
+        times.push(time);
+        instructions = count;
+    }
+    times.sort();
+    let median = times[RUNS / 2];
+    let seconds = median.as_secs() as f64 + median.subsec_nanos() as f64 * 1e-9;
+    (seconds, instructions)
+}
+
+fn main() {
+    let (path, story, commands) = common::story("cache");
+    println!(
+        "{}: {} commands, median over {} runs",
+        path,
+        commands.len(),
+        RUNS
+    );
+    let mut rates = Vec::new();
+    for (name, cache) in [("uncached", false), ("cached", true)] {
+        let (seconds, instructions) = measure(&story, &commands, cache);
+        let rate = instructions as f64 / seconds / 1e6;
+        println!(
+            "{:<8} {} instructions in {:.3} ms ({:.1} M instructions/s)",
+            name,
+            instructions,
+            seconds * 1000.0,
+            rate
+        );
+        rates.push(rate);
+    }
+    println!("speedup  {:.2}x", rates[1] / rates[0]);
+}
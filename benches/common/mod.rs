@@ -0,0 +1,89 @@
+// Story and script loading shared by the benchmarks.
+//
+// The story defaults to zork.z3 in the project root, played with the built-in Zork walkthrough.
+// Without it the benchmarks fall back to tests/stories/lantern.zil, compiled on the fly and played
+// with the commands of its transcript test, so a run can always be reproduced from the repository.
+// RUSTZORK_BENCH_STORY names another story, either a story file or .zasm or .zil source, and
+// RUSTZORK_BENCH_SCRIPT a file with one command per line to play instead.
+
+use rustzork::{assemble, compile};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_STORY: &str = "zork.z3";
+const FALLBACK_STORY: &str = "tests/stories/lantern.zil";
+const FALLBACK_SCRIPT: &str = "tests/transcripts/lantern.cmds";
+
+const WALKTHROUGH: &[&str] = &[
+    "open mailbox",
+    "take leaflet",
+    "read leaflet",
+    "drop leaflet",
+    "south",
+    "east",
+    "open window",
+    "enter house",
+    "take sack",
+    "open sack",
+    "west",
+    "take lamp",
+    "move rug",
+    "open trap door",
+    "turn on lamp",
+    "down",
+    "south",
+    "east",
+    "take painting",
+    "west",
+    "north",
+    "up",
+    "inventory",
+    "look",
+    "score",
+];
+
+// Returns the story path, its contents and the commands to play. A story named in
+// RUSTZORK_BENCH_STORY that can't be built stops the benchmark rather than being skipped.
+pub fn story(bench: &str) -> (String, Vec<u8>, Vec<String>) {
+    let (path, script) = match env::var("RUSTZORK_BENCH_STORY") {
+        Ok(path) => (path, None),
+        Err(_) if Path::new(DEFAULT_STORY).exists() => (DEFAULT_STORY.to_string(), None),
+        Err(_) => {
+            eprintln!(
+                "{} benchmark: no {}, using {} instead",
+                bench, DEFAULT_STORY, FALLBACK_STORY
+            );
+            (FALLBACK_STORY.to_string(), Some(FALLBACK_SCRIPT))
+        }
+    };
+    let story = build(&path).unwrap_or_else(|e| panic!("can't build {}: {}", path, e));
+    (path, story, script_or(script))
+}
+
+fn build(path: &str) -> Result<Vec<u8>, String> {
+    let source = || fs::read_to_string(path).map_err(|e| e.to_string());
+    match Path::new(path).extension().and_then(|x| x.to_str()) {
+        Some("zasm") => assemble(&source()?),
+        Some("zil") => assemble(&compile(&source()?)?),
+        _ => fs::read(path).map_err(|e| e.to_string()),
+    }
+}
+
+// The commands of RUSTZORK_BENCH_SCRIPT, else those of the transcript test given, else the Zork
+// walkthrough. Transcript headers are skipped.
+fn script_or(transcript: Option<&str>) -> Vec<String> {
+    let path = match env::var("RUSTZORK_BENCH_SCRIPT") {
+        Ok(path) => path,
+        Err(_) => match transcript {
+            Some(path) => path.to_string(),
+            None => return WALKTHROUGH.iter().map(|x| x.to_string()).collect(),
+        },
+    };
+    fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("can't read benchmark script {}: {}", path, e))
+        .lines()
+        .filter(|x| !x.starts_with('#'))
+        .map(|x| x.to_string())
+        .collect()
+}
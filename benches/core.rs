@@ -0,0 +1,229 @@
+// Micro-benchmarks of the interpreter core: Z-string decoding, instruction decoding, dictionary
+// lookup and single turns of a scripted game. Each benchmark is warmed up and then timed over a
+// number of samples, Criterion style. The median of each is kept in target/bench/core.txt and
+// the next run reports the change against it. See common/mod.rs for choosing the story and
+// script; any other argument filters benchmarks by name.
+//
+//     cargo bench --bench core [filter]
+
+extern crate rustzork;
+
+mod common;
+
+use rustzork::{Dictionary, Header, Instruction, Machine, Memory, Routine, ZString, ZIO};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RESULTS: &str = "target/bench/core.txt";
+const SAMPLES: usize = 50;
+const WARM_UP: Duration = Duration::from_millis(500);
+const MEASUREMENT: Duration = Duration::from_secs(2);
+const GAME_RUNS: usize = 10;
+
+struct Suite {
+    filter: Vec<String>,
+    previous: HashMap<String, f64>,
+    results: Vec<(String, f64)>,
+}
+
+impl Suite {
+    fn new() -> Suite {
+        let previous = fs::read_to_string(RESULTS)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let (name, time) = line.rsplit_once('\t')?;
+                Some((name.to_string(), time.parse().ok()?))
+            })
+            .collect();
+        Suite {
+            filter: env::args()
+                .skip(1)
+                .filter(|x| !x.starts_with('-'))
+                .collect(),
+            previous,
+            results: Vec::new(),
+        }
+    }
+
+    fn enabled(&self, name: &str) -> bool {
+        self.filter.is_empty() || self.filter.iter().any(|x| name.contains(x.as_str()))
+    }
+
+    // Times f, running it enough times per sample that timer resolution doesn't matter.
+    fn bench<T, F: FnMut() -> T>(&mut self, name: &str, mut f: F) {
+        if !self.enabled(name) {
+            return;
+        }
+        let mut iterations = 1u64;
+        let start = Instant::now();
+        let mut elapsed;
+        loop {
+            let sample = Instant::now();
+            for _ in 0..iterations {
+                black_box(f());
+            }
+            elapsed = sample.elapsed();
+            if start.elapsed() > WARM_UP {
+                break;
+            }
+            iterations *= 2;
+        }
+        let per_iteration = seconds(elapsed) / iterations as f64;
+        let target = seconds(MEASUREMENT) / SAMPLES as f64;
+        let iterations = ((target / per_iteration) as u64).max(1);
+
+        let mut samples = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            let sample = Instant::now();
+            for _ in 0..iterations {
+                black_box(f());
+            }
+            samples.push(seconds(sample.elapsed()) / iterations as f64);
+        }
+        self.record(name, samples);
+    }
+
+    fn record(&mut self, name: &str, mut samples: Vec<f64>) {
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let deviation = (samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt();
+        let median = samples[samples.len() / 2];
+        let change = match self.previous.get(name) {
+            Some(previous) => format!("{:+.1}%", (median / previous - 1.0) * 100.0),
+            None => "no previous run".to_string(),
+        };
+        println!(
+            "{:<40} median {:>10}  mean {:>10} ± {:>10}  range [{} .. {}]  change {}",
+            name,
+            format_time(median),
+            format_time(mean),
+            format_time(deviation),
+            format_time(samples[0]),
+            format_time(samples[samples.len() - 1]),
+            change
+        );
+        self.results.push((name.to_string(), median));
+    }
+
+    // Keeps this run's medians, along with earlier ones for benchmarks that were filtered out.
+    fn save(mut self) {
+        for (name, time) in self.results.drain(..) {
+            self.previous.insert(name, time);
+        }
+        let mut lines: Vec<_> = self
+            .previous
+            .iter()
+            .map(|(name, time)| format!("{}\t{}\n", name, time))
+            .collect();
+        lines.sort();
+        let _ = fs::create_dir_all("target/bench");
+        if let Err(e) = fs::write(RESULTS, lines.concat()) {
+            println!("can't save results to {}: {}", RESULTS, e);
+        }
+    }
+}
+
+fn seconds(x: Duration) -> f64 {
+    x.as_secs() as f64 + x.subsec_nanos() as f64 * 1e-9
+}
+
+fn format_time(x: f64) -> String {
+    if x < 1e-6 {
+        format!("{:.1} ns", x * 1e9)
+    } else if x < 1e-3 {
+        format!("{:.2} µs", x * 1e6)
+    } else {
+        format!("{:.3} ms", x * 1e3)
+    }
+}
+
+// Every abbreviation and object short name in the story.
+fn strings(memory: &Memory) -> Vec<usize> {
+    let table = memory.read_u16(0x18) as usize;
+    let mut strings: Vec<usize> = (0..96)
+        .map(|i| memory.read_u16(table + i * 2) as usize * 2)
+        .collect();
+
+    // Property tables follow the object table, so the first one marks its end.
+    let objects = memory.read_u16(0xa) as usize + 62;
+    let end = memory.read_u16(objects + 7) as usize;
+    for addr in (objects..end).step_by(9) {
+        let properties = memory.read_u16(addr + 7) as usize;
+        if memory.read_u8(properties) != 0 {
+            strings.push(properties + 1);
+        }
+    }
+    strings
+}
+
+fn main() {
+    let (path, story, commands) = common::story("core");
+    let memory = Memory::new(&story);
+    let header = Header::new(&memory);
+    println!("{}", path);
+
+    let mut suite = Suite::new();
+
+    let strings = strings(&memory);
+    suite.bench(&format!("zstring/new ({} strings)", strings.len()), || {
+        strings
+            .iter()
+            .map(|&x| ZString::new(&memory, x))
+            .collect::<Vec<_>>()
+    });
+
+    let instructions: Vec<usize> = Routine::find_all(&memory, &header)
+        .iter()
+        .flat_map(|r| r.instructions().iter().map(|i| i.offset()))
+        .collect();
+    suite.bench(
+        &format!("instruction/new ({} instructions)", instructions.len()),
+        || {
+            instructions
+                .iter()
+                .map(|&x| Instruction::new(&memory, x))
+                .collect::<Vec<_>>()
+        },
+    );
+
+    // Each word of the script, plus one that isn't in any dictionary.
+    let dictionary = Dictionary::new(&memory, memory.read_u16(0x8) as usize);
+    let mut words: Vec<&str> = commands.iter().flat_map(|x| x.split(' ')).collect();
+    words.push("xyzzyplugh");
+    suite.bench(
+        &format!("dictionary/get_word ({} words)", words.len()),
+        || {
+            words
+                .iter()
+                .map(|x| dictionary.get_word(x))
+                .collect::<Vec<_>>()
+        },
+    );
+
+    // Turns change the game state, so they can't be repeated. Instead each command of the script
+    // is timed once per playthrough, over several playthroughs.
+    let name = format!("game/turn ({} commands)", commands.len());
+    if suite.enabled(&name) {
+        let mut samples = Vec::new();
+        for _ in 0..GAME_RUNS {
+            let mut machine = Machine::new(Memory::new(&story), header);
+            machine.seed(0);
+            *machine.io() = ZIO::headless::<&str>(&[]);
+            machine.step(None);
+            for command in commands.iter() {
+                *machine.io() = ZIO::headless(&[command]);
+                let start = Instant::now();
+                machine.step(None);
+                samples.push(seconds(start.elapsed()));
+            }
+        }
+        suite.record(&name, samples);
+    }
+
+    suite.save();
+}
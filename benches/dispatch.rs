@@ -0,0 +1,49 @@
+// Times a scripted game run through the headless I/O, to measure instruction dispatch. See
+// common/mod.rs for choosing the story and script.
+//
+//     cargo bench --bench dispatch
+
+extern crate rustzork;
+
+mod common;
+
+use rustzork::{Header, Machine, Memory, ZIO};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 20;
+
+fn run(story: &[u8], commands: &[String]) -> (Duration, u64) {
+    let memory = Memory::new(story);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    machine.seed(0);
+    *machine.io() = ZIO::headless(commands);
+    let start = Instant::now();
+    machine.step(None);
+    (start.elapsed(), machine.instruction_count())
+}
+
+fn main() {
+    let (path, story, commands) = common::story("dispatch");
+
+    run(&story, &commands);
+    let mut times = Vec::new();
+    let mut instructions = 0;
+    for _ in 0..RUNS {
+        let (time, count) = run(&story, &commands);
+        times.push(time);
+        instructions = count;
+    }
+    times.sort();
+    let median = times[RUNS / 2];
+    let seconds = median.as_secs() as f64 + median.subsec_nanos() as f64 * 1e-9;
+    println!(
+        "{}: {} commands, {} instructions, median {:.3} ms over {} runs ({:.1} M instructions/s)",
+        path,
+        commands.len(),
+        instructions,
+        seconds * 1000.0,
+        RUNS,
+        instructions as f64 / seconds / 1e6
+    );
+}
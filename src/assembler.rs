@@ -0,0 +1,892 @@
+use instruction::{Encoding, Instruction};
+use opcode::Opcode;
+use std::collections::HashMap;
+
+// Assembles a V3 story file from text. Instructions are written the way Instruction's Display
+// prints them, with labels in place of addresses:
+//
+//     .routine main
+//     loop:
+//         PRINT "> "
+//         SREAD textbuf,parsebuf
+//         LOADW parsebuf,#01 -> -(SP)
+//         JE (SP)+,'quit' [TRUE] done
+//         CALL echo,#2a -> G00
+//         JUMP loop
+//     done:
+//         QUIT
+//
+// Operands are #xx (small) or #xxxx (large) hex constants, decimal numbers, the routine's locals
+// from L00, G00-Gef, (SP)+, a global, object or label name, or a dictionary word in single
+// quotes. Opcodes that take a variable by number, like INC and STORE, write it as [L00], [G00]
+// or [(SP)]. Labels of routines and strings are packed addresses, other labels byte addresses.
+// A branch is [TRUE] or [FALSE] followed by a label, RTRUE or RFALSE.
+//
+// Directives lay out the rest of the story:
+//
+//     .release 1                          release number
+//     .serial "260101"                    serial number
+//     .abbrev "the "                      next abbreviation, used when encoding strings
+//     .global score 0                     next global variable, with its initial value
+//     .object lamp "brass lamp" room      object, its short name and optional parent
+//     .attr 3 17                          attributes of the last object
+//     .prop 5 #07 #1234 label             property of the last object, as bytes and words
+//     .default 5 #0000                    default value of a property
+//     .table textbuf #28 #00              labelled bytes and words in dynamic memory
+//     .space 40                           zero bytes in dynamic memory
+//     .separators ".,"                    word separators of the dictionary
+//     .words take lamp quit               dictionary words
+//     .routine name 2                     routine with 2 locals; main must have none
+//     .routine name 3 1 -1                the same, with initial values of the first locals
+//     .string name "text"                 string in high memory
+//
+// Objects are numbered and their children linked in the order they are declared. Everything
+// after a semicolon is a comment.
+
+const HEADER_SIZE: usize = 0x40;
+const NUM_ABBREVIATIONS: usize = 96;
+const NUM_GLOBALS: usize = 240;
+const NUM_DEFAULTS: usize = 31;
+const WORD_LENGTH: usize = 4;
+const ENTRY_LENGTH: usize = 7;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Section {
+    Data,
+    Code,
+    // The value of a property, by object index and property number.
+    Property(usize, usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum LabelKind {
+    Packed,
+    Byte,
+}
+
+#[derive(Debug, Clone)]
+enum Arg {
+    Large(u16),
+    Small(u8),
+    Variable(u8),
+    Label(String),
+    Word(String),
+}
+
+enum Fix {
+    // A word holding the address of a label or dictionary word.
+    Address(Arg),
+    // A two byte branch offset to a label.
+    Branch(bool, String),
+    // The operand of jump, relative to the end of the instruction.
+    Jump(String),
+}
+
+struct Fixup {
+    line: usize,
+    section: Section,
+    offset: usize,
+    fix: Fix,
+}
+
+struct Object {
+    name: String,
+    short_name: String,
+    parent: Option<String>,
+    attributes: u32,
+    properties: Vec<(usize, Vec<u8>)>,
+}
+
+#[derive(Default)]
+struct Assembler {
+    line: usize,
+    release: u16,
+    serial: Vec<u8>,
+    abbreviations: Vec<String>,
+    globals: Vec<(String, u16)>,
+    global_names: HashMap<String, u8>,
+    object_names: HashMap<String, u8>,
+    objects: Vec<Object>,
+    defaults: Vec<u16>,
+    data: Vec<u8>,
+    separators: Vec<u8>,
+    words: Vec<String>,
+    code: Vec<u8>,
+    in_routine: bool,
+    locals: usize,
+    labels: HashMap<String, (Section, usize, LabelKind)>,
+    fixups: Vec<Fixup>,
+    main: Option<usize>,
+}
+
+fn write_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset] = (value >> 8) as u8;
+    buffer[offset + 1] = (value & 0xff) as u8;
+}
+
+fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value >> 8) as u8);
+    buffer.push((value & 0xff) as u8);
+}
+
+fn align(buffer: &mut Vec<u8>) {
+    if !buffer.len().is_multiple_of(2) {
+        buffer.push(0);
+    }
+}
+
+// The Z-characters of text, using abbreviations where they match.
+fn zchars(text: &str, abbreviations: &[String]) -> Vec<u8> {
+    const A0: &str = "abcdefghijklmnopqrstuvwxyz";
+    const A1: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    const A2: &str = "\n0123456789.,!?_#'\"/\\-:()";
+    let mut out = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let abbreviation = abbreviations
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| !x.is_empty() && rest.starts_with(x.as_str()))
+            .max_by_key(|(_, x)| x.len());
+        if let Some((index, x)) = abbreviation {
+            out.push(1 + (index / 32) as u8);
+            out.push((index % 32) as u8);
+            rest = &rest[x.len()..];
+            continue;
+        }
+        if c == ' ' {
+            out.push(0);
+        } else if let Some(i) = A0.find(c) {
+            out.push(6 + i as u8);
+        } else if let Some(i) = A1.find(c) {
+            out.extend_from_slice(&[4, 6 + i as u8]);
+        } else if let Some(i) = A2.find(c) {
+            out.extend_from_slice(&[5, 7 + i as u8]);
+        } else {
+            let c = c as u32 & 0x3ff;
+            out.extend_from_slice(&[5, 6, (c >> 5) as u8, (c & 0x1f) as u8]);
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+// Packs Z-characters three to a word, padding with 5s and marking the last word.
+fn pack(mut zchars: Vec<u8>, min_words: usize) -> Vec<u8> {
+    while !zchars.len().is_multiple_of(3) || zchars.len() < min_words * 3 {
+        zchars.push(5);
+    }
+    let mut out = Vec::new();
+    for x in zchars.chunks(3) {
+        push_u16(
+            &mut out,
+            ((x[0] as u16) << 10) | ((x[1] as u16) << 5) | (x[2] as u16),
+        );
+    }
+    let last = out.len() - 2;
+    out[last] |= 0x80;
+    out
+}
+
+// A dictionary word: the first six Z-characters, in exactly two words.
+fn encode_word(word: &str) -> Vec<u8> {
+    let mut x = zchars(word, &[]);
+    x.truncate(WORD_LENGTH / 2 * 3);
+    pack(x, WORD_LENGTH / 2)
+}
+
+// Splits a line into words, quoted strings and the punctuation of an instruction.
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Arrow,
+    Branch(bool),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => s.push('\n'),
+                        Some(x) => s.push(x),
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    Some(x) => s.push(x),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else {
+            let mut word = String::new();
+            while let Some(&x) = chars.peek() {
+                if x.is_whitespace() || x == ',' || x == ';' || x == '"' {
+                    break;
+                }
+                word.push(x);
+                chars.next();
+            }
+            tokens.push(match word.as_str() {
+                "->" => Token::Arrow,
+                "[TRUE]" => Token::Branch(true),
+                "[FALSE]" => Token::Branch(false),
+                _ => Token::Word(word),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<Arg> {
+    if let Some(hex) = text.strip_prefix('#') {
+        let x = u16::from_str_radix(hex, 16).ok()?;
+        return Some(if hex.len() <= 2 {
+            Arg::Small(x as u8)
+        } else {
+            Arg::Large(x)
+        });
+    }
+    let x: i32 = text.parse().ok()?;
+    match x {
+        0..=255 => Some(Arg::Small(x as u8)),
+        -32768..=65535 => Some(Arg::Large(x as u16)),
+        _ => None,
+    }
+}
+
+fn parse_u8(text: &str) -> Result<u8, String> {
+    match parse_number(text) {
+        Some(Arg::Small(x)) => Ok(x),
+        _ => Err(format!("expected a number from 0 to 255, not {:?}", text)),
+    }
+}
+
+fn parse_u16(text: &str) -> Result<u16, String> {
+    match parse_number(text) {
+        Some(Arg::Small(x)) => Ok(x as u16),
+        Some(Arg::Large(x)) => Ok(x),
+        _ => Err(format!("expected a number, not {:?}", text)),
+    }
+}
+
+fn string_token(tokens: &[Token], index: usize) -> Result<String, String> {
+    match tokens.get(index) {
+        Some(Token::Str(x)) => Ok(x.clone()),
+        _ => Err("expected a quoted string".to_string()),
+    }
+}
+
+fn word_token(tokens: &[Token], index: usize) -> Result<String, String> {
+    match tokens.get(index) {
+        Some(Token::Word(x)) => Ok(x.clone()),
+        _ => Err("expected a name".to_string()),
+    }
+}
+
+impl Assembler {
+    // The variable number of Lxx, Gxx, a global name or the stack.
+    fn variable(&self, text: &str) -> Option<u8> {
+        match text {
+            "(SP)+" | "(SP)" | "-(SP)" | "SP" => return Some(0),
+            _ => {}
+        }
+        if let Some(&x) = self.global_names.get(text) {
+            return Some(x);
+        }
+        let number = u8::from_str_radix(text.get(1..)?, 16).ok();
+        match (text.get(..1)?, number) {
+            ("L", Some(x)) if text.len() == 3 && (x as usize) < self.locals => Some(x + 1),
+            ("G", Some(x)) if text.len() == 3 && x < 0xf0 => Some(x + 0x10),
+            _ => None,
+        }
+    }
+
+    fn operand(&self, text: &str) -> Result<Arg, String> {
+        if let Some(x) = parse_number(text) {
+            return Ok(x);
+        }
+        if let Some(x) = self.variable(text) {
+            return Ok(Arg::Variable(x));
+        }
+        if text.len() == 3 && text.starts_with('L') && u8::from_str_radix(&text[1..], 16).is_ok() {
+            return Err(format!("the routine has no local {}", text));
+        }
+        if let Some(inner) = text.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            return self
+                .variable(inner)
+                .map(Arg::Small)
+                .ok_or(format!("{:?} is not a variable", inner));
+        }
+        if let Some(word) = text.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')) {
+            return Ok(Arg::Word(word.to_string()));
+        }
+        if let Some(&x) = self.object_names.get(text) {
+            return Ok(Arg::Small(x));
+        }
+        if text.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Ok(Arg::Label(text.to_string()));
+        }
+        Err(format!("can't read operand {:?}", text))
+    }
+
+    // Bytes and words of a table or property. Labels and dictionary words take a word.
+    fn datum(&mut self, text: &str, out: &mut Vec<u8>, section: Section) -> Result<(), String> {
+        match self.operand(text)? {
+            Arg::Small(x) => out.push(x),
+            Arg::Large(x) => push_u16(out, x),
+            Arg::Variable(_) => return Err(format!("{:?} is a variable", text)),
+            arg => {
+                self.fixups.push(Fixup {
+                    line: self.line,
+                    section,
+                    offset: out.len(),
+                    fix: Fix::Address(arg),
+                });
+                push_u16(out, 0);
+            }
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, section: Section, kind: LabelKind) -> Result<(), String> {
+        let offset = match section {
+            Section::Code => self.code.len(),
+            _ => self.data.len(),
+        };
+        if self
+            .labels
+            .insert(name.to_string(), (section, offset, kind))
+            .is_some()
+        {
+            return Err(format!("label {} is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn object(&mut self) -> Result<&mut Object, String> {
+        self.objects
+            .last_mut()
+            .ok_or("no object to add to".to_string())
+    }
+
+    fn directive(&mut self, tokens: &[Token]) -> Result<(), String> {
+        let name = word_token(tokens, 0)?;
+        let words: Vec<String> = tokens[1..]
+            .iter()
+            .filter_map(|x| match x {
+                Token::Word(x) => Some(x.clone()),
+                _ => None,
+            })
+            .collect();
+        match name.as_str() {
+            ".release" => self.release = parse_u16(&word_token(tokens, 1)?)?,
+            ".serial" => {
+                let serial = string_token(tokens, 1)?;
+                if serial.len() != 6 {
+                    return Err("the serial number must be 6 characters".to_string());
+                }
+                self.serial = serial.into_bytes();
+            }
+            ".abbrev" => {
+                if self.abbreviations.len() == NUM_ABBREVIATIONS {
+                    return Err("too many abbreviations".to_string());
+                }
+                self.abbreviations.push(string_token(tokens, 1)?);
+            }
+            ".global" => {
+                let value = match words.get(1) {
+                    Some(x) => parse_u16(x)?,
+                    None => 0,
+                };
+                self.globals.push((word_token(tokens, 1)?, value));
+            }
+            ".object" => {
+                self.objects.push(Object {
+                    name: word_token(tokens, 1)?,
+                    short_name: string_token(tokens, 2)?,
+                    parent: match tokens.get(3) {
+                        Some(Token::Word(x)) => Some(x.clone()),
+                        _ => None,
+                    },
+                    attributes: 0,
+                    properties: Vec::new(),
+                });
+            }
+            ".attr" => {
+                for x in words {
+                    let attribute = parse_u8(&x)?;
+                    if attribute > 31 {
+                        return Err(format!("attribute {} is out of range", attribute));
+                    }
+                    self.object()?.attributes |= 0x8000_0000 >> attribute;
+                }
+            }
+            ".prop" => {
+                let number = parse_u8(&word_token(tokens, 1)?)? as usize;
+                if number == 0 || number > NUM_DEFAULTS {
+                    return Err(format!("property {} is out of range", number));
+                }
+                let index = self.objects.len().wrapping_sub(1);
+                let mut value = Vec::new();
+                for x in &words[1..] {
+                    self.datum(x, &mut value, Section::Property(index, number))?;
+                }
+                if value.is_empty() || value.len() > 8 {
+                    return Err("a property has from 1 to 8 bytes".to_string());
+                }
+                let object = self.object()?;
+                if object.properties.iter().any(|x| x.0 == number) {
+                    return Err(format!("property {} is already set", number));
+                }
+                object.properties.push((number, value));
+            }
+            ".default" => {
+                let number = parse_u8(&word_token(tokens, 1)?)? as usize;
+                if number == 0 || number > NUM_DEFAULTS {
+                    return Err(format!("property {} is out of range", number));
+                }
+                self.defaults[number - 1] = parse_u16(&word_token(tokens, 2)?)?;
+            }
+            ".table" => {
+                self.define(&word_token(tokens, 1)?, Section::Data, LabelKind::Byte)?;
+                let mut data = std::mem::take(&mut self.data);
+                let result = words[1..]
+                    .iter()
+                    .try_for_each(|x| self.datum(x, &mut data, Section::Data));
+                self.data = data;
+                result?;
+            }
+            ".space" => {
+                let n: usize = word_token(tokens, 1)?
+                    .parse()
+                    .map_err(|_| "expected a byte count".to_string())?;
+                self.data.resize(self.data.len() + n, 0);
+            }
+            ".separators" => self.separators = string_token(tokens, 1)?.into_bytes(),
+            ".words" => self.words.extend(words),
+            ".routine" => {
+                let name = word_token(tokens, 1)?;
+                let locals = match words.get(1) {
+                    Some(x) => x
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&x| x <= 15)
+                        .ok_or("a routine has from 0 to 15 locals".to_string())?,
+                    None => 0,
+                };
+                if words.len() > locals + 2 {
+                    return Err("more initial values than locals".to_string());
+                }
+                align(&mut self.code);
+                self.in_routine = true;
+                self.locals = locals;
+                if name == "main" {
+                    if locals != 0 {
+                        return Err("main can't have locals".to_string());
+                    }
+                    self.main = Some(self.code.len());
+                }
+                self.define(&name, Section::Code, LabelKind::Packed)?;
+                self.code.push(locals as u8);
+                for i in 0..locals {
+                    let value = match words.get(i + 2) {
+                        Some(x) => parse_u16(x)?,
+                        None => 0,
+                    };
+                    push_u16(&mut self.code, value);
+                }
+            }
+            ".string" => {
+                let name = word_token(tokens, 1)?;
+                align(&mut self.code);
+                self.in_routine = false;
+                self.define(&name, Section::Code, LabelKind::Packed)?;
+                let text = pack(zchars(&string_token(tokens, 2)?, &self.abbreviations), 1);
+                self.code.extend(text);
+            }
+            _ => return Err(format!("unknown directive {}", name)),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, tokens: &[Token]) -> Result<(), String> {
+        let mnemonic = word_token(tokens, 0)?.to_lowercase();
+        let (optype, opcode) =
+            Opcode::find(&mnemonic).ok_or(format!("unknown opcode {}", mnemonic))?;
+        let op = Opcode::new(optype, opcode);
+
+        let mut args = Vec::new();
+        let mut store = None;
+        let mut string = None;
+        let mut branch = None;
+        let mut it = tokens[1..].iter();
+        while let Some(token) = it.next() {
+            match token {
+                Token::Word(x) if store.is_none() && string.is_none() && branch.is_none() => {
+                    args.push(self.operand(x)?)
+                }
+                Token::Arrow => match it.next() {
+                    Some(Token::Word(x)) => {
+                        store = Some(
+                            self.variable(x)
+                                .ok_or(format!("{:?} is not a variable", x))?,
+                        )
+                    }
+                    _ => return Err("expected a variable after ->".to_string()),
+                },
+                Token::Str(x) => string = Some(x.clone()),
+                Token::Branch(compare) => match it.next() {
+                    Some(Token::Word(x)) => branch = Some((*compare, x.clone())),
+                    _ => return Err("expected a label after the branch condition".to_string()),
+                },
+                Token::Word(x) => return Err(format!("unexpected {:?}", x)),
+            }
+        }
+
+        let count_ok = match optype {
+            Encoding::Op0 => args.is_empty(),
+            Encoding::Op1 => args.len() == 1,
+            Encoding::Op2 => args.len() >= 2 && args.len() <= 4,
+            Encoding::Var => args.len() <= 4,
+        };
+        if !count_ok {
+            return Err(format!("wrong number of operands for {}", mnemonic));
+        }
+        if Instruction::stores(optype, opcode) != store.is_some() {
+            return Err(match store {
+                Some(_) => format!("{} doesn't store a result", mnemonic),
+                None => format!("{} needs -> and a variable to store to", mnemonic),
+            });
+        }
+        if Instruction::branches(optype, opcode) != branch.is_some() {
+            return Err(match branch {
+                Some(_) => format!("{} doesn't branch", mnemonic),
+                None => format!("{} needs [TRUE] or [FALSE] and a label", mnemonic),
+            });
+        }
+        let prints = op == Opcode::Print || op == Opcode::PrintRet;
+        if prints != string.is_some() {
+            return Err(match string {
+                Some(_) => format!("{} doesn't take a string", mnemonic),
+                None => format!("{} needs a string", mnemonic),
+            });
+        }
+
+        // Type bits of each operand: 0 large, 1 small, 2 variable.
+        let kind = |arg: &Arg| match *arg {
+            Arg::Small(_) => 1,
+            Arg::Variable(_) => 2,
+            _ => 0,
+        };
+        match optype {
+            Encoding::Op0 => self.code.push(0xb0 | opcode as u8),
+            Encoding::Op1 => self.code.push(0x80 | (kind(&args[0]) << 4) | opcode as u8),
+            Encoding::Op2 if args.len() == 2 && args.iter().all(|x| kind(x) != 0) => {
+                let bits = ((kind(&args[0]) - 1) << 6) | ((kind(&args[1]) - 1) << 5);
+                self.code.push(bits | opcode as u8);
+            }
+            _ => {
+                let form = if let Encoding::Var = optype {
+                    0xe0
+                } else {
+                    0xc0
+                };
+                self.code.push(form | opcode as u8);
+                let mut types = 0xffu8;
+                for (i, arg) in args.iter().enumerate() {
+                    let shift = 6 - 2 * i;
+                    types = (types & !(3 << shift)) | (kind(arg) << shift);
+                }
+                self.code.push(types);
+            }
+        }
+        for arg in args {
+            match arg {
+                Arg::Small(x) | Arg::Variable(x) => self.code.push(x),
+                Arg::Large(x) => push_u16(&mut self.code, x),
+                arg => {
+                    let fix = match arg {
+                        Arg::Label(ref x) if op == Opcode::Jump => Fix::Jump(x.clone()),
+                        arg => Fix::Address(arg),
+                    };
+                    self.fixups.push(Fixup {
+                        line: self.line,
+                        section: Section::Code,
+                        offset: self.code.len(),
+                        fix,
+                    });
+                    push_u16(&mut self.code, 0);
+                }
+            }
+        }
+        if let Some(x) = store {
+            self.code.push(x);
+        }
+        if let Some((compare, target)) = branch {
+            let flag = if compare { 0x80 } else { 0 };
+            match target.as_str() {
+                "RFALSE" => self.code.push(flag | 0x40),
+                "RTRUE" => self.code.push(flag | 0x41),
+                _ => {
+                    self.fixups.push(Fixup {
+                        line: self.line,
+                        section: Section::Code,
+                        offset: self.code.len(),
+                        fix: Fix::Branch(compare, target),
+                    });
+                    push_u16(&mut self.code, 0);
+                }
+            }
+        }
+        if let Some(x) = string {
+            let text = pack(zchars(&x, &self.abbreviations), 1);
+            self.code.extend(text);
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, text: &str) -> Result<(), String> {
+        let mut text = text.trim();
+        if let Some(colon) = text.find(':') {
+            let label = &text[..colon];
+            if !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                if !self.in_routine {
+                    return Err("labels of code must be inside a routine".to_string());
+                }
+                self.define(label, Section::Code, LabelKind::Byte)?;
+                text = text[colon + 1..].trim();
+            }
+        }
+        let tokens = tokenize(text)?;
+        match tokens.first() {
+            None => Ok(()),
+            Some(Token::Word(x)) if x.starts_with('.') => self.directive(&tokens),
+            Some(Token::Word(_)) if self.in_routine => self.instruction(&tokens),
+            Some(Token::Word(_)) => Err("instructions must be inside a routine".to_string()),
+            Some(_) => Err("expected an opcode or directive".to_string()),
+        }
+    }
+
+    // Names of globals and objects, which have to be known before any code refers to them.
+    fn declare(&mut self, source: &str) -> Result<(), String> {
+        let (mut globals, mut objects) = (0, 0);
+        for (n, text) in source.lines().enumerate() {
+            let tokens = tokenize(text).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            let (directive, name) = match (tokens.first(), tokens.get(1)) {
+                (Some(Token::Word(x)), Some(Token::Word(y))) => (x.as_str(), y.clone()),
+                _ => continue,
+            };
+            match directive {
+                ".global" if globals == NUM_GLOBALS => {
+                    return Err(format!("line {}: too many globals", n + 1))
+                }
+                ".global" => {
+                    self.global_names.insert(name, 0x10 + globals as u8);
+                    globals += 1;
+                }
+                ".object" if objects == 255 => {
+                    return Err(format!("line {}: too many objects", n + 1))
+                }
+                ".object" => {
+                    objects += 1;
+                    self.object_names.insert(name, objects as u8);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Lays the sections out after the header and resolves every reference.
+    fn link(mut self) -> Result<Vec<u8>, String> {
+        let main = self.main.ok_or("there is no main routine")?;
+        let mut story = vec![0u8; HEADER_SIZE];
+
+        // Abbreviations, with unused entries pointing at an empty string.
+        let mut entries = Vec::new();
+        for x in self.abbreviations.iter() {
+            entries.push(story.len() / 2);
+            story.extend(pack(zchars(x, &[]), 1));
+        }
+        let empty = story.len() / 2;
+        story.extend(pack(Vec::new(), 1));
+        let abbreviation_table = story.len();
+        for i in 0..NUM_ABBREVIATIONS {
+            push_u16(&mut story, *entries.get(i).unwrap_or(&empty) as u16);
+        }
+
+        // Object tree, then each object's property table.
+        let object_table = story.len();
+        for x in self.defaults.iter() {
+            push_u16(&mut story, *x);
+        }
+        let objects_start = story.len();
+        story.resize(objects_start + 9 * self.objects.len(), 0);
+        let mut last_child: HashMap<u8, u8> = HashMap::new();
+        let mut property_starts = Vec::new();
+        for (i, object) in self.objects.iter().enumerate() {
+            let index = i as u8 + 1;
+            let entry = objects_start + 9 * i;
+            write_u16(&mut story, entry, (object.attributes >> 16) as u16);
+            write_u16(&mut story, entry + 2, (object.attributes & 0xffff) as u16);
+            if let Some(ref parent) = object.parent {
+                let parent = *self.object_names.get(parent).ok_or(format!(
+                    "object {} has an unknown parent {}",
+                    object.name, parent
+                ))?;
+                story[entry + 4] = parent;
+                match last_child.insert(parent, index) {
+                    Some(sibling) => story[objects_start + 9 * (sibling as usize - 1) + 5] = index,
+                    None => story[objects_start + 9 * (parent as usize - 1) + 6] = index,
+                }
+            }
+        }
+        for (i, object) in self.objects.iter_mut().enumerate() {
+            let properties = story.len();
+            write_u16(&mut story, objects_start + 9 * i + 7, properties as u16);
+            let name = if object.short_name.is_empty() {
+                Vec::new()
+            } else {
+                pack(zchars(&object.short_name, &self.abbreviations), 0)
+            };
+            story.push((name.len() / 2) as u8);
+            story.extend(name);
+            object.properties.sort_by_key(|x| std::cmp::Reverse(x.0));
+            let mut starts = HashMap::new();
+            for (number, value) in object.properties.iter() {
+                story.push((32 * (value.len() - 1) + number) as u8);
+                starts.insert(*number, story.len());
+                story.extend(value);
+            }
+            story.push(0);
+            property_starts.push(starts);
+        }
+
+        let globals = story.len();
+        let mut values = vec![0u16; NUM_GLOBALS];
+        for (i, &(_, value)) in self.globals.iter().enumerate() {
+            values[i] = value;
+        }
+        for x in values {
+            push_u16(&mut story, x);
+        }
+
+        let data = story.len();
+        story.extend(self.data.iter());
+        align(&mut story);
+        let static_start = story.len();
+
+        // The dictionary, sorted by encoded word.
+        let dictionary = story.len();
+        story.push(self.separators.len() as u8);
+        story.extend(self.separators.iter());
+        story.push(ENTRY_LENGTH as u8);
+        let mut words: Vec<(Vec<u8>, &String)> =
+            self.words.iter().map(|x| (encode_word(x), x)).collect();
+        words.sort();
+        words.dedup_by(|a, b| a.0 == b.0);
+        push_u16(&mut story, words.len() as u16);
+        let mut word_addresses = HashMap::new();
+        for (encoded, _) in words {
+            word_addresses.insert(encoded.clone(), story.len());
+            story.extend(encoded);
+            story.extend_from_slice(&[0, 0, 0]);
+        }
+
+        align(&mut story);
+        let high = story.len();
+        story.extend(self.code.iter());
+        align(&mut story);
+
+        for fixup in self.fixups.iter() {
+            let at = match fixup.section {
+                Section::Data => data + fixup.offset,
+                Section::Code => high + fixup.offset,
+                Section::Property(object, number) => {
+                    property_starts[object][&number] + fixup.offset
+                }
+            };
+            let error = |e: String| format!("line {}: {}", fixup.line, e);
+            let label = |name: &str| {
+                self.labels
+                    .get(name)
+                    .map(|&(section, offset, kind)| match section {
+                        Section::Code => (high + offset, kind),
+                        _ => (data + offset, kind),
+                    })
+                    .ok_or(format!("unknown label {}", name))
+            };
+            let value = match fixup.fix {
+                Fix::Address(Arg::Word(ref word)) => *word_addresses
+                    .get(&encode_word(word))
+                    .ok_or_else(|| error(format!("{:?} isn't in the dictionary", word)))?
+                    as i32,
+                Fix::Address(Arg::Label(ref name)) => match label(name).map_err(error)? {
+                    (addr, LabelKind::Packed) => (addr / 2) as i32,
+                    (addr, LabelKind::Byte) => addr as i32,
+                },
+                Fix::Jump(ref name) => label(name).map_err(error)?.0 as i32 - at as i32,
+                Fix::Branch(compare, ref name) => {
+                    let offset = label(name).map_err(error)?.0 as i32 - at as i32;
+                    if !(-0x2000..0x2000).contains(&offset) {
+                        return Err(error(format!("branch to {} is too far", name)));
+                    }
+                    (if compare { 0x8000 } else { 0 }) | (offset & 0x3fff)
+                }
+                Fix::Address(_) => unreachable!(),
+            };
+            if !(-0x8000..0x10000).contains(&value) {
+                return Err(error("address doesn't fit in a word".to_string()));
+            }
+            write_u16(&mut story, at, value as u16);
+        }
+
+        story[0] = 3;
+        write_u16(&mut story, 0x2, self.release);
+        write_u16(&mut story, 0x4, high as u16);
+        write_u16(&mut story, 0x6, (high + main + 1) as u16);
+        write_u16(&mut story, 0x8, dictionary as u16);
+        write_u16(&mut story, 0xa, object_table as u16);
+        write_u16(&mut story, 0xc, globals as u16);
+        write_u16(&mut story, 0xe, static_start as u16);
+        story[0x12..0x18].copy_from_slice(&self.serial);
+        write_u16(&mut story, 0x18, abbreviation_table as u16);
+        if story.len() > 0x20000 {
+            return Err("the story is larger than 128K".to_string());
+        }
+        let length = story.len() / 2;
+        write_u16(&mut story, 0x1a, length as u16);
+        let checksum = story[HEADER_SIZE..]
+            .iter()
+            .fold(0u16, |sum, &x| sum.wrapping_add(x as u16));
+        write_u16(&mut story, 0x1c, checksum);
+        Ok(story)
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler {
+        serial: b"000000".to_vec(),
+        defaults: vec![0; NUM_DEFAULTS],
+        ..Default::default()
+    };
+    assembler.declare(source)?;
+    for (n, text) in source.lines().enumerate() {
+        assembler.line = n + 1;
+        assembler
+            .line(text)
+            .map_err(|e| format!("line {}: {}", n + 1, e))?;
+    }
+    assembler.link()
+}
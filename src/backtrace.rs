@@ -0,0 +1,94 @@
+use instruction::Return;
+use memory::Memory;
+use std::cmp;
+use std::fmt;
+
+// One routine activation on the call stack. The main routine has no frame of its own, so it has
+// no return address and no locals.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameInfo {
+    pub routine: usize,
+    pub pc: usize,
+    pub return_addr: Option<usize>,
+    pub store: Option<u8>,
+    pub locals: Vec<u16>,
+    pub stack: Vec<u16>,
+}
+
+// The call stack of a machine, innermost frame first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backtrace {
+    pub frames: Vec<FrameInfo>,
+}
+
+impl Backtrace {
+    pub fn new(memory: &Memory, main: usize, ip: usize) -> Backtrace {
+        let mut frames = Vec::new();
+        let mut pc = ip;
+        let mut stack_end = memory.stack.len();
+        for frame in memory.frames.iter().rev() {
+            let locals_end = cmp::min(frame.stack_start + frame.num_locals, stack_end);
+            frames.push(FrameInfo {
+                routine: frame.addr,
+                pc,
+                return_addr: Some(frame.return_addr),
+                store: match frame.return_storage {
+                    Return::Variable(x) | Return::Indirect(x) => Some(x),
+                    Return::Omitted => None,
+                },
+                locals: memory.stack[frame.stack_start..locals_end].to_vec(),
+                stack: memory.stack[locals_end..stack_end].to_vec(),
+            });
+            pc = frame.return_addr;
+            stack_end = frame.stack_start;
+        }
+        frames.push(FrameInfo {
+            routine: main,
+            pc,
+            return_addr: None,
+            store: None,
+            locals: Vec::new(),
+            stack: memory.stack[..stack_end].to_vec(),
+        });
+        Backtrace { frames }
+    }
+}
+
+fn words(values: &[u16]) -> String {
+    if values.is_empty() {
+        return "(empty)".to_string();
+    }
+    let values: Vec<String> = values.iter().map(|x| format!("{:04x}", x)).collect();
+    values.join(" ")
+}
+
+impl fmt::Display for FrameInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.return_addr {
+            Some(addr) => {
+                let store = match self.store {
+                    Some(x) => format!("{}", Return::Variable(x)),
+                    None => String::new(),
+                };
+                writeln!(
+                    f,
+                    "routine {:08X} at {:08X}, returns to {:08X}{}",
+                    self.routine, self.pc, addr, store
+                )?;
+                writeln!(f, "      locals: {}", words(&self.locals))?;
+            }
+            None => writeln!(f, "main {:08X} at {:08X}", self.routine, self.pc)?,
+        }
+        write!(f, "      stack:  {}", words(&self.stack))
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backtrace (most recent call first):")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "\n  #{} {}", i, frame)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use header::Header;
+use memory::Memory;
+use routine::Routine;
+use std::cmp;
+use std::collections::HashSet;
+
+// Records the address of every executed instruction so it can be reported against the
+// disassembly of the story's routines.
+pub struct Coverage {
+    pub(crate) output: String,
+    pub(crate) executed: HashSet<usize>,
+}
+
+impl Coverage {
+    pub fn new(output: String) -> Coverage {
+        Coverage {
+            output,
+            executed: HashSet::new(),
+        }
+    }
+
+    pub fn record(&mut self, offset: usize) {
+        self.executed.insert(offset);
+    }
+
+    pub fn report(&self, memory: &Memory, header: &Header) -> String {
+        let routines = Routine::find_all(memory, header);
+        let mut known = HashSet::new();
+        let mut total = 0;
+        let mut covered = 0;
+        let mut entered = 0;
+        let mut summary = String::new();
+        let mut listing = String::new();
+        for r in routines.iter() {
+            let hits = r
+                .instructions
+                .iter()
+                .filter(|i| self.executed.contains(&i.offset))
+                .count();
+            let count = r.instructions.len();
+            total += count;
+            covered += hits;
+            if hits > 0 {
+                entered += 1;
+            }
+            let line = format!(
+                "Routine {:08X}: {}/{} ({:.1}%)\n",
+                r.addr,
+                hits,
+                count,
+                100.0 * hits as f64 / count as f64
+            );
+            summary += &line;
+            listing += &format!("\n{}", line);
+            for i in r.instructions.iter() {
+                known.insert(i.offset);
+                let mark = if self.executed.contains(&i.offset) {
+                    '*'
+                } else {
+                    ' '
+                };
+                listing += &format!("{} {}\n", mark, i);
+            }
+        }
+        let mut out = format!(
+            "Coverage: {}/{} instructions ({:.1}%), {}/{} routines entered\n",
+            covered,
+            total,
+            100.0 * covered as f64 / cmp::max(total, 1) as f64,
+            entered,
+            routines.len()
+        );
+        let mut unknown: Vec<_> = self.executed.difference(&known).collect();
+        if !unknown.is_empty() {
+            unknown.sort();
+            let unknown: Vec<String> = unknown.iter().map(|x| format!("{:08X}", x)).collect();
+            out += &format!(
+                "Executed outside disassembled routines: {}\n",
+                unknown.join(" ")
+            );
+        }
+        out + "\n" + &summary + &listing
+    }
+}
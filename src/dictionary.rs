@@ -0,0 +1,61 @@
+use memory::Memory;
+use zstring::ZString;
+
+#[derive(Debug, Clone)]
+pub struct Dictionary {
+    pub(crate) offset: usize,
+    pub(crate) separators: Vec<char>,
+    pub(crate) entry_length: usize,
+    pub(crate) words: Vec<ZString>,
+}
+
+impl Dictionary {
+    pub fn new(memory: &Memory, offset: usize) -> Dictionary {
+        let mut separators: Vec<char> = Vec::new();
+        let mut words: Vec<ZString> = Vec::new();
+
+        let num_separators = memory.read_u8(offset) as usize;
+        for i in 0..num_separators {
+            separators.push(memory.read_u8(offset + i + 1) as char);
+        }
+
+        let entry_start = offset + num_separators + 1;
+        let entry_length = memory.read_u8(entry_start) as usize;
+        let num_entries = memory.read_u16(entry_start + 1) as usize;
+
+        for i in 0..num_entries {
+            // A corrupt count can claim more entries than the story holds.
+            if entry_start + 3 + i * entry_length >= memory.len() {
+                break;
+            }
+            words.push(ZString::with_max_length(
+                memory,
+                entry_start + 3 + i * entry_length,
+                4,
+            ));
+        }
+
+        Dictionary {
+            offset,
+            separators,
+            entry_length,
+            words,
+        }
+    }
+
+    pub fn get_word(&self, token: &str) -> Option<ZString> {
+        for word in self.words.iter() {
+            // 4 byte zstring stores max 6 characters
+            if word.contents.len() < 6 {
+                if word.contents == token {
+                    return Some(word.clone());
+                }
+            } else {
+                if token.starts_with(&word.contents) {
+                    return Some(word.clone());
+                }
+            }
+        }
+        None
+    }
+}
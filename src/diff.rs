@@ -0,0 +1,167 @@
+// Differences between two states of the same story, in the game's terms where that's possible:
+// globals by number, objects by name with where they are, their attributes and properties, other
+// dynamic memory as runs of bytes, and the frames of the call stack.
+
+use backtrace::{Backtrace, FrameInfo};
+use memory::Memory;
+use object::{object_count, Object, OBJECT_SIZE};
+use std::cmp;
+
+const NUM_GLOBALS: usize = 240;
+
+// An object's number and short name, or "nothing" for 0.
+fn named(memory: &Memory, index: usize) -> String {
+    match index {
+        0 => "nothing".to_string(),
+        x => format!("{} \"{}\"", x, Object::new(memory, x).name(memory)),
+    }
+}
+
+fn bytes(memory: &Memory, start: usize, length: usize) -> String {
+    (start..start + length)
+        .map(|x| format!("{:02x}", memory.read_u8(x)))
+        .collect()
+}
+
+// What changed in one object: its parent, attributes and property values. Siblings and children
+// follow from the parents, so changes to them aren't listed.
+fn object(before: &Memory, after: &Memory, index: usize) -> Vec<String> {
+    let old = Object::new(before, index);
+    let new = Object::new(after, index);
+    let mut lines = Vec::new();
+    if old.parent(before) != new.parent(after) {
+        lines.push(format!(
+            "parent: {} -> {}",
+            named(before, old.parent(before)),
+            named(after, new.parent(after))
+        ));
+    }
+    let attributes: Vec<String> = (0..32)
+        .filter(|&x| old.test_attr(before, x) != new.test_attr(after, x))
+        .map(|x| match new.test_attr(after, x) {
+            true => format!("+{}", x),
+            false => format!("-{}", x),
+        })
+        .collect();
+    if !attributes.is_empty() {
+        lines.push(format!("attributes: {}", attributes.join(" ")));
+    }
+    for p in new.properties(after).0 {
+        let old_value = bytes(before, p.offset + 1, p.length);
+        let new_value = bytes(after, p.offset + 1, p.length);
+        if old_value != new_value {
+            lines.push(format!(
+                "property {}: {} -> {}",
+                p.index, old_value, new_value
+            ));
+        }
+    }
+    lines
+}
+
+// A report of what differs between the dynamic memory of before and after, the first dynamic_end
+// bytes, with the globals table at globals. Empty if nothing does.
+pub(crate) fn memory(
+    before: &Memory,
+    after: &Memory,
+    globals: usize,
+    dynamic_end: usize,
+) -> String {
+    let changed = |x: usize| before.read_u8(x) != after.read_u8(x);
+    let mut seen = vec![false; dynamic_end];
+    let mut mark = |start: usize, end: usize| {
+        for x in seen.iter_mut().take(end).skip(start) {
+            *x = true;
+        }
+    };
+    let mut out = String::new();
+
+    let mut lines = Vec::new();
+    for n in 0..NUM_GLOBALS {
+        let addr = globals + n * 2;
+        if before.read_u16(addr) != after.read_u16(addr) {
+            lines.push(format!(
+                "  G{:02x}: {:04x} -> {:04x}\n",
+                n,
+                before.read_u16(addr),
+                after.read_u16(addr)
+            ));
+        }
+    }
+    mark(globals, globals + NUM_GLOBALS * 2);
+    if !lines.is_empty() {
+        out += "Globals:\n";
+        out += &lines.concat();
+    }
+
+    let mut lines = Vec::new();
+    for index in 1..=object_count(before) {
+        let entry = Object::new(after, index);
+        let table = entry.prop_table(after);
+        let end = entry.properties(after).1;
+        mark(entry.addr, entry.addr + OBJECT_SIZE);
+        mark(table, end);
+        let touched = (entry.addr..entry.addr + OBJECT_SIZE)
+            .chain(table..end)
+            .any(changed);
+        if !touched {
+            continue;
+        }
+        let changes = object(before, after, index);
+        if !changes.is_empty() {
+            lines.push(format!("  {}\n", named(after, index)));
+            for change in changes {
+                lines.push(format!("      {}\n", change));
+            }
+        }
+    }
+    if !lines.is_empty() {
+        out += "Objects:\n";
+        out += &lines.concat();
+    }
+
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for x in (0..dynamic_end).filter(|&x| !seen[x] && changed(x)) {
+        match runs.last_mut() {
+            Some(run) if run.1 == x => run.1 = x + 1,
+            _ => runs.push((x, x + 1)),
+        }
+    }
+    if !runs.is_empty() {
+        out += "Other memory:\n";
+        for (start, end) in runs {
+            out += &format!("  {:04x}: {}\n", start, bytes(after, start, end - start));
+        }
+    }
+    out
+}
+
+// The frame n frames in from the outermost, with its number counting from the innermost.
+fn outer(frames: &[FrameInfo], n: usize) -> Option<(usize, &FrameInfo)> {
+    let i = frames.len().checked_sub(n + 1)?;
+    Some((i, &frames[i]))
+}
+
+// A report of the frames of the call stack that differ, matched up from the outermost, or nothing
+// if the stacks are the same. Each differing frame is shown as it was and as it is.
+pub(crate) fn stacks(before: &Backtrace, after: &Backtrace) -> String {
+    let depth = cmp::max(before.frames.len(), after.frames.len());
+    let mut out = String::new();
+    for n in 0..depth {
+        let old = outer(&before.frames, n);
+        let new = outer(&after.frames, n);
+        if old.map(|x| x.1) == new.map(|x| x.1) {
+            continue;
+        }
+        if let Some((i, frame)) = old {
+            out += &format!("  - #{} {}\n", i, frame);
+        }
+        if let Some((i, frame)) = new {
+            out += &format!("  + #{} {}\n", i, frame);
+        }
+    }
+    if out.is_empty() {
+        return out;
+    }
+    format!("Call stack:\n{}", out)
+}
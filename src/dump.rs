@@ -0,0 +1,206 @@
+use diff;
+use machine::Machine;
+use quetzal;
+use routine::Routine;
+use snapshot::Snapshot;
+use zstring::ZString;
+
+impl Machine {
+    pub fn dump(&mut self, table: &str) {
+        let out = match table {
+            "dictionary" => self.dump_dictionary(),
+            "abbreviations" => self.dump_abbreviations(),
+            _ => self.dump_strings(),
+        };
+        self.io.print(&out);
+    }
+
+    // A summary of the header, with the checksum checked against the story's contents.
+    pub fn info(&self) -> String {
+        let memory = &self.memory;
+        let serial: String = (0x12..0x18).map(|x| memory.read_u8(x) as char).collect();
+        let stored = self.header.checksum as u16;
+        let verdict = if self.checksum == stored {
+            "ok".to_string()
+        } else {
+            format!("corrupted, the story sums to {:04x}", self.checksum)
+        };
+        let mut out = format!(
+            "Version {}, release {}, serial {}\n",
+            memory.read_u8(0),
+            memory.read_u16(0x2),
+            serial
+        );
+        out += &format!(
+            "Length:        {} bytes ({} in the file)\n",
+            self.header.length,
+            memory.len()
+        );
+        out += &format!("Static memory: {:04x}\n", self.header.static_start);
+        out += &format!("High memory:   {:04x}\n", self.header.high_start);
+        out += &format!("Initial PC:    {:04x}\n", memory.read_u16(0x6));
+        out += &format!("Dictionary:    {:04x}\n", memory.read_u16(0x8));
+        out += &format!("Objects:       {:04x}\n", memory.read_u16(0xa));
+        out += &format!("Globals:       {:04x}\n", self.header.globals);
+        out += &format!("Abbreviations: {:04x}\n", memory.read_u16(0x18));
+        out += &format!("Checksum:      {:04x}, {}\n", stored, verdict);
+        out
+    }
+
+    // Whether the story as loaded matches the checksum in its header.
+    pub fn verify(&self) -> bool {
+        self.checksum == self.header.checksum as u16
+    }
+
+    // The game as it would be with the save restored, after checking the save is of this story.
+    fn restored(&self, data: &[u8]) -> Result<Machine, String> {
+        let chunks = quetzal::chunks(data)?;
+        let header = chunks
+            .iter()
+            .find(|x| &x.0 == b"IFhd")
+            .map(|x| x.1)
+            .ok_or("the save has no IFhd chunk")?;
+        let id = quetzal::identity(&self.memory);
+        if header.len() >= 10 && header[..10] != id[..] {
+            return Err(format!(
+                "the save is of {}, but the story is {}",
+                quetzal::describe(header),
+                quetzal::describe(&id)
+            ));
+        }
+        let mut machine = self.clone();
+        machine.restart();
+        machine.restore_game(data)?;
+        Ok(machine)
+    }
+
+    // A description of a rustzork save of this story: its chunks, the call stack it was saved with
+    // and what the game had changed since it started.
+    pub fn save_info(&self, data: &[u8]) -> Result<String, String> {
+        let saved = self.restored(data)?;
+        let chunks = quetzal::chunks(data)?;
+        let sizes: Vec<String> = chunks
+            .iter()
+            .map(|x| format!("{} ({} bytes)", String::from_utf8_lossy(&x.0), x.1.len()))
+            .collect();
+        let mut out = format!("Chunks: {}\n", sizes.join(", "));
+        out += &format!(
+            "Story:  {}, matches\n",
+            quetzal::describe(&quetzal::identity(&self.memory))
+        );
+        if let Some(note) = quetzal::annotation(data) {
+            out += &format!("Note:   {}\n", note);
+        }
+        if let Some(x) = chunks.iter().find(|x| &x.0 == b"CMem" || &x.0 == b"UMem") {
+            out += &format!(
+                "Memory: {}, {} bytes for {} bytes of dynamic memory\n",
+                String::from_utf8_lossy(&x.0),
+                x.1.len(),
+                self.initial.len()
+            );
+        }
+        out += &format!("\n{}\n\n", saved.backtrace());
+        let mut start = self.clone();
+        start.restart();
+        out += &match start.changes(&saved) {
+            x if x.is_empty() => "Dynamic memory is unchanged.\n".to_string(),
+            x => x,
+        };
+        Ok(out)
+    }
+
+    fn changes(&self, other: &Machine) -> String {
+        diff::memory(
+            &self.memory,
+            &other.memory,
+            self.header.globals,
+            self.initial.len(),
+        )
+    }
+
+    // What differs between two states of this game: moved objects, attributes, properties,
+    // globals, other dynamic memory and the call stack.
+    pub fn compare(&self, before: &Snapshot, after: &Snapshot) -> Result<String, String> {
+        let mut old = self.clone();
+        old.restore_snapshot(before)?;
+        let mut new = self.clone();
+        new.restore_snapshot(after)?;
+        Ok(old.difference(&new))
+    }
+
+    // What differs between two saves of this game, as for compare.
+    pub fn compare_saves(&self, before: &[u8], after: &[u8]) -> Result<String, String> {
+        let old = self.restored(before)?;
+        let new = self.restored(after)?;
+        Ok(old.difference(&new))
+    }
+
+    fn difference(&self, other: &Machine) -> String {
+        let out = self.changes(other) + &diff::stacks(&self.backtrace(), &other.backtrace());
+        match out.is_empty() {
+            true => "The games are the same.\n".to_string(),
+            false => out,
+        }
+    }
+
+    // A save of this story converted to keep memory compressed in a CMem chunk or whole in UMem.
+    pub fn convert_save(&self, data: &[u8], compressed: bool) -> Result<Vec<u8>, String> {
+        self.restored(data)?;
+        quetzal::convert(data, &self.initial, compressed)
+    }
+
+    pub fn dump_dictionary(&self) -> String {
+        let dictionary = &self.dictionary;
+        let separators: Vec<String> = dictionary
+            .separators
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        let mut out = format!(
+            "Dictionary at {:04x}: {} entries of {} bytes\nSeparators: {}\n",
+            dictionary.offset,
+            dictionary.words.len(),
+            dictionary.entry_length,
+            separators.join(" ")
+        );
+        for word in dictionary.words.iter() {
+            let data: Vec<String> = (4..dictionary.entry_length)
+                .map(|i| format!("{:02x}", self.memory.read_u8(word.offset + i)))
+                .collect();
+            out += &format!(
+                "[{:08X}] {:<8}{}\n",
+                word.offset,
+                word.contents,
+                data.join(" ")
+            );
+        }
+        out
+    }
+
+    pub fn dump_abbreviations(&self) -> String {
+        let table = self.memory.read_u16(0x18) as usize;
+        let mut out = format!("Abbreviations at {:04x}:\n", table);
+        for index in 0..96 {
+            let addr = self.memory.read_u16(table + index * 2) as usize * 2;
+            let zs = ZString::new(&self.memory, addr);
+            out += &format!("[{:08X}] {:2} {:?}\n", addr, index, zs.contents);
+        }
+        out
+    }
+
+    // Strings reachable through print_paddr live in high memory after the last routine.
+    pub fn dump_strings(&self) -> String {
+        let routines = Routine::find_all(&self.memory, &self.header);
+        let mut offset = match routines.last() {
+            Some(r) => (r.end() + 1) & !1,
+            None => (self.header.high_start + 1) & !1,
+        };
+        let mut out = format!("Strings at {:04x}:\n", offset);
+        while ZString::fits(&self.memory, offset) {
+            let zs = ZString::new(&self.memory, offset);
+            out += &format!("[{:08X}] {:04x} {:?}\n", offset, offset / 2, zs.contents);
+            offset += zs.length;
+        }
+        out
+    }
+}
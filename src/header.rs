@@ -0,0 +1,55 @@
+use memory::Memory;
+use std::cmp;
+
+const HEADER_SIZE: usize = 0x40;
+
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct Header {
+    pub(crate) dynamic_start: usize,
+    pub(crate) dynamic_end: usize,
+    pub(crate) static_start: usize,
+    pub(crate) static_end: usize,
+    pub(crate) high_start: usize,
+    pub(crate) high_end: usize,
+    pub(crate) globals: usize,
+    pub(crate) length: usize,
+    pub(crate) checksum: usize,
+}
+
+impl Header {
+    pub fn new(mem: &Memory) -> Header {
+        let dynamic_start = 0;
+        let dynamic_end = mem.read_u16(0xe) as usize;
+        let static_start = dynamic_end;
+        let static_end = static_start + cmp::min(0xffff, mem.len());
+        let high_start = mem.read_u16(0x4) as usize;
+        let high_end = mem.len();
+        let globals = mem.read_u16(0xc) as usize;
+        // Version 3 stores the length in words. Early stories leave it 0, meaning the whole file.
+        let length = match mem.read_u16(0x1a) as usize * 2 {
+            0 => mem.len(),
+            x => cmp::min(x, mem.len()),
+        };
+        let checksum = mem.read_u16(0x1c) as usize;
+
+        Header {
+            dynamic_start,
+            dynamic_end,
+            static_start,
+            static_end,
+            high_start,
+            high_end,
+            globals,
+            length,
+            checksum,
+        }
+    }
+}
+
+// The sum of the bytes after the header up to the file length given in the header, which VERIFY
+// compares with the checksum stored in the header. Only meaningful before the game has changed
+// dynamic memory.
+pub fn checksum(memory: &Memory, header: &Header) -> u16 {
+    (HEADER_SIZE..header.length).fold(0u16, |sum, x| sum.wrapping_add(memory.read_u8(x) as u16))
+}
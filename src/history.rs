@@ -0,0 +1,85 @@
+// A record of what each executed instruction changed, so the machine can be run backwards. Each
+// change keeps what it replaced: the old value of a byte of memory or a stack slot, a popped value
+// or a popped call frame. Output already printed and input already read can't be taken back, so
+// running forwards again over a read reads another command.
+
+use memory::{Frame, Memory};
+use rand::rngs::SmallRng;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone)]
+pub(crate) enum Change {
+    // A byte of memory was written, and what it was.
+    Byte(usize, u8),
+    Push,
+    Pop(u16),
+    // A local or other slot of the stack was set, and what it was.
+    Stack(usize, u16),
+    Call,
+    Return(Frame),
+}
+
+// One executed instruction: where it was, the random number generator if it could have used it,
+// and what it changed, oldest first.
+#[derive(Debug, Clone)]
+pub(crate) struct Step {
+    pub(crate) ip: usize,
+    pub(crate) rng: Option<SmallRng>,
+    pub(crate) changes: Vec<Change>,
+}
+
+impl Step {
+    // Whether the instruction wrote any byte from addr up to addr + length.
+    pub(crate) fn wrote(&self, addr: usize, length: usize) -> bool {
+        self.changes.iter().any(|x| match *x {
+            Change::Byte(offset, _) => offset >= addr && offset < addr + length,
+            _ => false,
+        })
+    }
+}
+
+// The most recent instructions, newest last, up to a limit. A limit of 0 records nothing.
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    pub(crate) steps: VecDeque<Step>,
+    limit: usize,
+}
+
+impl History {
+    pub(crate) fn new(limit: usize) -> History {
+        History {
+            steps: VecDeque::new(),
+            limit,
+        }
+    }
+
+    pub(crate) fn record(&mut self, step: Step) {
+        if self.limit == 0 {
+            return;
+        }
+        while self.steps.len() >= self.limit {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+}
+
+impl Memory {
+    // Takes back an instruction's changes, newest first.
+    pub(crate) fn revert(&mut self, changes: &[Change]) {
+        for change in changes.iter().rev() {
+            match *change {
+                Change::Byte(offset, x) => self.memory[offset] = x,
+                Change::Push => {
+                    self.stack.pop();
+                }
+                Change::Pop(x) => self.stack.push(x),
+                Change::Stack(index, x) => self.stack[index] = x,
+                Change::Call => {
+                    self.frames.pop();
+                }
+                Change::Return(frame) => self.frames.push(frame),
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use memory::Memory;
+use opcode::Opcode;
+use std::fmt;
+use std::ops::Deref;
+use zstring::ZString;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Operand {
+    Large(u16),
+    Small(u8),
+    Variable(u8),
+    Indirect(u8),
+    Omitted,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Large(x) => write!(f, "#{:04x}", x),
+            Operand::Small(x) => write!(f, "#{:02x}", x),
+            Operand::Variable(0) => write!(f, "(SP)+"),
+            Operand::Variable(x) if x >= 0x10 => write!(f, "G{:02x}", x - 0x10),
+            Operand::Variable(x) => write!(f, "L{:02x}", x - 1),
+            Operand::Indirect(0) => write!(f, "[(SP)]"),
+            Operand::Indirect(x) if x >= 0x10 => write!(f, "[G{:02x}]", x - 0x10),
+            Operand::Indirect(x) => write!(f, "[L{:02x}]", x - 1),
+            Operand::Omitted => write!(f, ""),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Return {
+    Variable(u8),
+    Indirect(u8),
+    Omitted,
+}
+
+impl fmt::Display for Return {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Return::Variable(0) => write!(f, " -> -(SP)"),
+            Return::Variable(x) if x >= 0x10 => write!(f, " -> G{:02x}", x - 0x10),
+            Return::Variable(x) => write!(f, " -> L{:02x}", x - 1),
+            Return::Indirect(0) => write!(f, " -> (SP)"),
+            Return::Indirect(x) if x >= 0x10 => write!(f, " -> G{:02x}", x - 0x10),
+            Return::Indirect(x) => write!(f, " -> L{:02x}", x - 1),
+            _ => write!(f, ""),
+        }
+    }
+}
+
+// Up to four operands stored inline, so decoding doesn't allocate.
+#[derive(Debug, Copy, Clone)]
+pub struct Operands {
+    values: [Operand; 4],
+    len: usize,
+}
+
+impl Operands {
+    fn new() -> Operands {
+        Operands {
+            values: [Operand::Omitted; 4],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, x: Operand) {
+        self.values[self.len] = x;
+        self.len += 1;
+    }
+}
+
+impl Deref for Operands {
+    type Target = [Operand];
+
+    fn deref(&self) -> &[Operand] {
+        &self.values[..self.len]
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Encoding {
+    Op0,
+    Op1,
+    Op2,
+    Var,
+}
+
+#[derive(Debug)]
+pub struct Instruction {
+    pub(crate) offset: usize,
+    pub(crate) opcode: usize,
+    pub(crate) optype: Encoding,
+    pub(crate) op: Opcode,
+    pub(crate) length: usize,
+    pub(crate) args: Operands,
+    pub(crate) ret: Return,
+    pub(crate) string: Option<ZString>,
+    pub(crate) jump_offset: Option<i32>,
+    pub(crate) compare: Option<bool>,
+}
+
+pub const MAX_INSTRUCTION_LENGTH: usize = 13;
+
+impl Instruction {
+    pub fn name(&self) -> &'static str {
+        self.op.name()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    // The nth operand, or Omitted if the instruction has fewer.
+    pub(crate) fn arg(&self, n: usize) -> Operand {
+        self.args.get(n).cloned().unwrap_or(Operand::Omitted)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match self.optype {
+            Encoding::Op0 => self.opcode <= 0xd,
+            Encoding::Op1 => self.opcode <= 0xf,
+            Encoding::Op2 => self.opcode >= 0x1 && self.opcode <= 0x18,
+            Encoding::Var => self.opcode <= 0xb || (self.opcode >= 0x13 && self.opcode <= 0x15),
+        }
+    }
+
+    // Control never falls through to the next instruction.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self.op,
+            Opcode::Rtrue
+                | Opcode::Rfalse
+                | Opcode::PrintRet
+                | Opcode::Restart
+                | Opcode::RetPopped
+                | Opcode::Quit
+                | Opcode::Ret
+                | Opcode::Jump
+        )
+    }
+
+    pub fn branch_target(&self) -> Option<usize> {
+        let next = (self.offset + self.length) as i32;
+        match (self.op, self.jump_offset) {
+            (Opcode::Jump, _) => match self.args[0] {
+                Operand::Large(x) => Some((next + x as i16 as i32 - 2) as usize),
+                _ => None,
+            },
+            (_, Some(0)) | (_, Some(1)) | (_, None) => None,
+            (_, Some(x)) => Some((next + x - 2) as usize),
+        }
+    }
+
+    fn decode_short(memory: &Memory, offset: usize, op: u8) -> Instruction {
+        let mut args = Operands::new();
+        let (optype, length) = match (op & 0x30) >> 4 {
+            3 => (Encoding::Op0, 1),
+            2 => {
+                args.push(Operand::Variable(memory.read_u8(offset + 1)));
+                (Encoding::Op1, 2)
+            }
+            1 => {
+                args.push(Operand::Small(memory.read_u8(offset + 1)));
+                (Encoding::Op1, 2)
+            }
+            _ => {
+                args.push(Operand::Large(memory.read_u16(offset + 1)));
+                (Encoding::Op1, 3)
+            }
+        };
+        let opcode = (op & 0xf) as usize;
+        Instruction {
+            offset,
+            opcode,
+            optype,
+            op: Opcode::new(optype, opcode),
+            length,
+            args,
+            ret: Return::Omitted,
+            string: None,
+            jump_offset: None,
+            compare: None,
+        }
+    }
+
+    fn decode_long(memory: &Memory, offset: usize, op: u8) -> Instruction {
+        let x = memory.read_u8(offset + 1);
+        let y = memory.read_u8(offset + 2);
+        let mut args = Operands::new();
+        args.push(if (op & 0x40) != 0 {
+            Operand::Variable(x)
+        } else {
+            Operand::Small(x)
+        });
+        args.push(if (op & 0x20) != 0 {
+            Operand::Variable(y)
+        } else {
+            Operand::Small(y)
+        });
+        let opcode = (op & 0x1f) as usize;
+        Instruction {
+            offset,
+            opcode,
+            optype: Encoding::Op2,
+            op: Opcode::new(Encoding::Op2, opcode),
+            length: 3,
+            args,
+            ret: Return::Omitted,
+            string: None,
+            jump_offset: None,
+            compare: None,
+        }
+    }
+
+    fn decode_var(memory: &Memory, offset: usize, op: u8) -> Instruction {
+        let optypes = memory.read_u8(offset + 1);
+        let mut size = 2;
+        let mut args = Operands::new();
+        for x in 0..4 {
+            let shift = (3 - x) * 2;
+            let mask = 3 << shift;
+            match (optypes & mask) >> shift {
+                3 => {}
+                2 => {
+                    size += 1;
+                    args.push(Operand::Variable(memory.read_u8(offset + size - 1)));
+                }
+                1 => {
+                    size += 1;
+                    args.push(Operand::Small(memory.read_u8(offset + size - 1)));
+                }
+                _ => {
+                    size += 2;
+                    args.push(Operand::Large(memory.read_u16(offset + size - 2)));
+                }
+            }
+        }
+        let opcode = (op & 0x1f) as usize;
+        let optype = if (op & 0x20) != 0 {
+            Encoding::Var
+        } else {
+            Encoding::Op2
+        };
+        Instruction {
+            offset,
+            opcode,
+            optype,
+            op: Opcode::new(optype, opcode),
+            length: size,
+            args,
+            ret: Return::Omitted,
+            string: None,
+            jump_offset: None,
+            compare: None,
+        }
+    }
+
+    // Whether the opcode is followed by a store variable.
+    pub(crate) fn stores(optype: Encoding, opcode: usize) -> bool {
+        match optype {
+            Encoding::Op2 => (0x08..=0x09).contains(&opcode) || (0x0f..=0x19).contains(&opcode),
+            Encoding::Op1 => {
+                (0x01..=0x04).contains(&opcode) || opcode == 0x08 || (0x0e..=0x0f).contains(&opcode)
+            }
+            Encoding::Var => opcode == 0x0 || opcode == 0x7,
+            _ => false,
+        }
+    }
+
+    // Whether the opcode is followed by a branch offset.
+    pub(crate) fn branches(optype: Encoding, opcode: usize) -> bool {
+        match optype {
+            Encoding::Op2 => (1..=7).contains(&opcode) || opcode == 10,
+            Encoding::Op1 => opcode <= 2,
+            Encoding::Op0 => opcode == 5 || opcode == 6 || opcode == 0xd || opcode == 0xf,
+            _ => false,
+        }
+    }
+
+    fn add_return(&mut self, memory: &Memory) {
+        if Instruction::stores(self.optype, self.opcode) {
+            self.ret = Return::Variable(memory.read_u8(self.offset + self.length));
+            self.length += 1;
+        }
+    }
+
+    fn add_branch(&mut self, memory: &Memory) {
+        if Instruction::branches(self.optype, self.opcode) {
+            let branch1 = memory.read_u8(self.offset + self.length) as i32;
+            let mut offset = (0x80 & branch1) << 8;
+            let len = if (branch1 & 0x40) != 0 {
+                offset |= branch1 & 0x3f;
+                1
+            } else {
+                let branch2 = memory.read_u8(self.offset + self.length + 1) as i32;
+                offset |= (branch1 & 0x3f) << 8;
+                offset |= branch2;
+                2
+            };
+            let compare = (offset & 0x8000) != 0;
+            offset &= 0x7fff;
+            // Long offsets are 14 bit signed numbers.
+            if offset > 0x1fff {
+                offset -= 0x4000;
+            }
+            self.jump_offset = Some(offset);
+            self.length += len;
+            self.compare = Some(compare);
+        }
+    }
+
+    fn add_print(&mut self, memory: &Memory) {
+        if self.op == Opcode::Print || self.op == Opcode::PrintRet {
+            let s = ZString::new(memory, self.offset + self.length);
+            self.length += s.length;
+            self.string = Some(s);
+        }
+    }
+
+    pub fn new(memory: &Memory, offset: usize) -> Instruction {
+        let op = memory.read_u8(offset);
+        let mut i = match (op & 0xc0) >> 6 {
+            3 => Instruction::decode_var(memory, offset, op),
+            2 => Instruction::decode_short(memory, offset, op),
+            _ => Instruction::decode_long(memory, offset, op),
+        };
+        i.add_return(memory);
+        i.add_branch(memory);
+        i.add_print(memory);
+        i
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|a| format!("{}", a)).collect();
+        let string = if let Some(ref x) = self.string {
+            format!(" \"{}\"", x)
+        } else {
+            String::new()
+        };
+        let compare = if let Some(x) = self.compare {
+            format!(" [{}]", x.to_string().to_uppercase())
+        } else {
+            String::new()
+        };
+        let offset = if let Some(x) = self.jump_offset {
+            match x {
+                0 => " RFALSE".to_string(),
+                1 => " RTRUE".to_string(),
+                _ => format!(" {:08X}", (self.offset + self.length) as i32 + x - 2),
+            }
+        } else {
+            String::new()
+        };
+        write!(
+            f,
+            "[{:08X}] {}\t{}{}{}{}{}",
+            self.offset,
+            self.name().to_uppercase(),
+            args.join(","),
+            self.ret,
+            string,
+            compare,
+            offset
+        )
+    }
+}
@@ -0,0 +1,383 @@
+#[cfg(feature = "cli")]
+use std::collections::VecDeque;
+#[cfg(feature = "cli")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "cli")]
+use std::io::Write;
+#[cfg(feature = "cli")]
+use std::path::PathBuf;
+#[cfg(feature = "cli")]
+use std::sync::Arc;
+#[cfg(feature = "cli")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "cli"))]
+extern "C" {
+    fn clear();
+    #[allow(dead_code)]
+    fn debug_trace(x: i32);
+    fn terminal_height() -> i32;
+    fn put_line(x: i32, y: i32, text: *const u8, len: i32);
+    fn now() -> f64;
+    // Named saves, kept by the page under keys starting with the save directory. Reads and lists
+    // return the length of the data, or -1 if there is none, and only fill in the buffer if it is
+    // big enough.
+    fn slot_write(name: *const u8, name_len: i32, data: *const u8, len: i32) -> i32;
+    fn slot_read(name: *const u8, name_len: i32, data: *mut u8, len: i32) -> i32;
+    fn slot_delete(name: *const u8, name_len: i32) -> i32;
+    fn slot_list(dir: *const u8, dir_len: i32, data: *mut u8, len: i32) -> i32;
+}
+
+#[cfg(not(feature = "cli"))]
+#[derive(Clone)]
+enum InputState {
+    None,
+    Listening,
+    Consuming,
+}
+
+#[cfg(not(feature = "cli"))]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub struct ZIO {
+    buffer: String,
+    input: String,
+    flushed: bool,
+    state: InputState,
+    saves: Option<String>,
+}
+
+#[cfg(not(feature = "cli"))]
+impl ZIO {
+    pub(crate) fn new() -> ZIO {
+        ZIO {
+            buffer: String::new(),
+            input: String::new(),
+            flushed: true,
+            state: InputState::None,
+            saves: None,
+        }
+    }
+    pub fn print(&mut self, s: &str) {
+        if s.ends_with("n") {
+            self.flushed = false;
+        }
+        self.buffer += s;
+    }
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.flushed = false;
+        Ok(())
+    }
+    pub fn log(&mut self, s: &str) {
+        self.buffer += s;
+        self.buffer += "\n";
+        self.flushed = false;
+    }
+    pub fn trace(&mut self, s: &str) {
+        self.log(s);
+    }
+    pub fn start_transcript(&mut self, _path: &str) -> Result<(), std::io::Error> {
+        Err(std::io::Error::other(
+            "transcripts aren't available in the browser",
+        ))
+    }
+    pub fn stop_transcript(&mut self) {}
+    pub fn autosave(&mut self, _path: &str, _data: &[u8]) -> Result<(), std::io::Error> {
+        Err(std::io::Error::other(
+            "autosaves aren't available in the browser",
+        ))
+    }
+    pub fn forget_autosave(&mut self, _path: &str) {}
+
+    // Seconds since 1970.
+    pub fn now(&self) -> u64 {
+        (unsafe { now() } / 1000.0) as u64
+    }
+    // Keeps named saves in the page's local storage, under keys starting with dir.
+    pub fn save_dir(&mut self, dir: &str) {
+        self.saves = Some(format!("{}/", dir));
+    }
+    fn slot_key(&self, name: &str) -> Result<String, String> {
+        match self.saves {
+            Some(ref dir) => Ok(format!("{}{}", dir, name)),
+            None => Err("there's nowhere to keep saves".to_string()),
+        }
+    }
+    pub fn write_slot(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
+        let key = self.slot_key(name)?;
+        match unsafe {
+            slot_write(
+                key.as_ptr(),
+                key.len() as i32,
+                data.as_ptr(),
+                data.len() as i32,
+            )
+        } {
+            0 => Ok(()),
+            _ => Err("the page's storage is full".to_string()),
+        }
+    }
+    pub fn read_slot(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        let key = self.slot_key(name)?;
+        let len = unsafe { slot_read(key.as_ptr(), key.len() as i32, std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err("there's no such save".to_string());
+        }
+        let mut data = vec![0; len as usize];
+        unsafe { slot_read(key.as_ptr(), key.len() as i32, data.as_mut_ptr(), len) };
+        Ok(data)
+    }
+    pub fn delete_slot(&mut self, name: &str) -> Result<(), String> {
+        let key = self.slot_key(name)?;
+        match unsafe { slot_delete(key.as_ptr(), key.len() as i32) } {
+            0 => Ok(()),
+            _ => Err("there's no such save".to_string()),
+        }
+    }
+    // The names of the saves, in order.
+    pub fn slots(&mut self) -> Vec<String> {
+        let dir = match self.saves {
+            Some(ref dir) => dir.clone(),
+            None => return Vec::new(),
+        };
+        let len = unsafe { slot_list(dir.as_ptr(), dir.len() as i32, std::ptr::null_mut(), 0) };
+        if len <= 0 {
+            return Vec::new();
+        }
+        let mut data = vec![0; len as usize];
+        unsafe { slot_list(dir.as_ptr(), dir.len() as i32, data.as_mut_ptr(), len) };
+        let mut names: Vec<String> = String::from_utf8_lossy(&data)
+            .lines()
+            .map(|x| x.to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn key_down(&mut self, key: u8) {
+        if let InputState::Listening = self.state {
+            if key == 13 {
+                self.buffer.push('\n');
+                self.state = InputState::Consuming;
+            } else {
+                self.buffer.push(key as char);
+                self.input.push(key as char);
+            }
+            self.flushed = false;
+        }
+    }
+
+    pub fn poll_input(&mut self) -> bool {
+        if let InputState::Consuming = self.state {
+            true
+        } else {
+            if let InputState::None = self.state {
+                self.buffer.push(' ');
+                self.state = InputState::Listening;
+                self.input = String::new();
+            }
+            false
+        }
+    }
+
+    pub fn input(&mut self) -> String {
+        self.state = InputState::None;
+        self.input.clone()
+    }
+    pub fn draw(&mut self) {
+        if !self.flushed {
+            self.flushed = true;
+            unsafe {
+                clear();
+            }
+            let max_lines = unsafe { terminal_height() } as usize;
+            let lines: Vec<_> = self.buffer.lines().collect();
+            let start = if lines.len() > max_lines {
+                lines.len() - max_lines
+            } else {
+                0
+            };
+            for (y, l) in lines[start..].iter().enumerate() {
+                unsafe {
+                    put_line(0, y as i32, l.as_ptr(), l.len() as i32);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub struct ZIO {
+    input: String,
+    script: Option<VecDeque<String>>,
+    output: Option<String>,
+    // Where a copy of the game's output and the player's commands goes, while transcribing.
+    transcript: Option<Arc<File>>,
+    // The directory of named saves, one file each.
+    saves: Option<PathBuf>,
+}
+
+#[cfg(feature = "cli")]
+impl ZIO {
+    pub(crate) fn new() -> ZIO {
+        ZIO {
+            input: String::new(),
+            script: None,
+            output: None,
+            transcript: None,
+            saves: None,
+        }
+    }
+    // Reads input from a list of commands instead of stdin and collects output instead of
+    // printing it. Commands are echoed to the output as if typed, and input runs out once they
+    // are used up.
+    pub fn headless<S: AsRef<str>>(commands: &[S]) -> ZIO {
+        ZIO {
+            input: String::new(),
+            script: Some(commands.iter().map(|x| x.as_ref().to_string()).collect()),
+            output: Some(String::new()),
+            transcript: None,
+            saves: None,
+        }
+    }
+    pub fn take_output(&mut self) -> String {
+        match self.output {
+            Some(ref mut output) => std::mem::take(output),
+            None => String::new(),
+        }
+    }
+    pub fn print(&mut self, s: &str) {
+        self.transcribe(s);
+        match self.output {
+            Some(ref mut output) => output.push_str(s),
+            None => print!("{}", s),
+        }
+    }
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        use std::io::Write;
+        std::io::stdout().flush()
+    }
+    pub fn log(&mut self, s: &str) {
+        self.print(s);
+        self.print("\n");
+    }
+    // The instruction trace of debug builds. Headless runs leave it out so that their output is
+    // the same as in release builds.
+    pub fn trace(&mut self, s: &str) {
+        if self.output.is_none() {
+            self.log(s);
+        }
+    }
+    // Appends to the transcript file if there is one. A transcript that can't be written to is
+    // not worth stopping the game for.
+    fn transcribe(&mut self, s: &str) {
+        if let Some(ref file) = self.transcript {
+            let _ = (&**file).write_all(s.as_bytes());
+        }
+    }
+    // Starts copying output and commands to the end of the file at path.
+    pub fn start_transcript(&mut self, path: &str) -> Result<(), std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.transcript = Some(Arc::new(file));
+        Ok(())
+    }
+    pub fn stop_transcript(&mut self) {
+        self.transcript = None;
+    }
+    // Replaces the autosave at path. The save is written to a file next to it and then renamed
+    // over it, so a crash or a full disk part way through leaves the last autosave intact.
+    pub fn autosave(&mut self, path: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let temporary = format!("{}.tmp", path);
+        let mut file = File::create(&temporary)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)
+    }
+    // Removes the autosave at path, if there is one.
+    pub fn forget_autosave(&mut self, path: &str) {
+        let _ = std::fs::remove_file(path);
+    }
+    // Seconds since 1970. Headless runs always start 1970, so that their output is the same
+    // every time.
+    pub fn now(&self) -> u64 {
+        match self.script {
+            Some(_) => 0,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+        }
+    }
+    // Keeps named saves in dir, which is made when the first one is written.
+    pub fn save_dir(&mut self, dir: &str) {
+        self.saves = Some(PathBuf::from(dir));
+    }
+    fn slot_path(&self, name: &str) -> Result<PathBuf, String> {
+        match self.saves {
+            Some(ref dir) => Ok(dir.join(format!("{}.qzl", name))),
+            None => Err("there's nowhere to keep saves".to_string()),
+        }
+    }
+    pub fn write_slot(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
+        let path = self.slot_path(name)?;
+        std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(&path, data))
+            .map_err(|e| e.to_string())
+    }
+    pub fn read_slot(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        let path = self.slot_path(name)?;
+        std::fs::read(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => "there's no such save".to_string(),
+            _ => e.to_string(),
+        })
+    }
+    pub fn delete_slot(&mut self, name: &str) -> Result<(), String> {
+        let path = self.slot_path(name)?;
+        std::fs::remove_file(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => "there's no such save".to_string(),
+            _ => e.to_string(),
+        })
+    }
+    // The names of the saves, in order.
+    pub fn slots(&mut self) -> Vec<String> {
+        let dir = match self.saves.as_ref().and_then(|x| std::fs::read_dir(x).ok()) {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let mut names: Vec<String> = dir
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.extension().is_some_and(|x| x == "qzl"))
+            .filter_map(|x| x.file_stem().map(|x| x.to_string_lossy().to_string()))
+            .collect();
+        names.sort();
+        names
+    }
+    pub fn poll_input(&mut self) -> bool {
+        self.input = String::new();
+        if let Some(ref mut script) = self.script {
+            return match script.pop_front() {
+                Some(line) => {
+                    if let Some(ref mut output) = self.output {
+                        output.push_str(&line);
+                        output.push('\n');
+                    }
+                    self.transcribe(&format!("{}\n", line));
+                    self.input = line;
+                    true
+                }
+                None => false,
+            };
+        }
+        let stdin = std::io::stdin();
+        // Nothing read means the input has ended.
+        let ok = matches!(stdin.read_line(&mut self.input), Ok(n) if n > 0);
+        let line = self.input.clone();
+        self.transcribe(&line);
+        ok
+    }
+    pub fn input(&self) -> String {
+        self.input.clone()
+    }
+}
@@ -0,0 +1,38 @@
+extern crate rand;
+
+mod assembler;
+mod backtrace;
+mod coverage;
+mod dictionary;
+mod diff;
+mod dump;
+mod header;
+mod history;
+mod instruction;
+mod io;
+mod machine;
+mod memory;
+mod meta;
+mod object;
+mod opcode;
+mod profiler;
+mod quetzal;
+mod routine;
+mod snapshot;
+mod zil;
+mod zstring;
+
+pub use assembler::assemble;
+pub use backtrace::{Backtrace, FrameInfo};
+pub use dictionary::Dictionary;
+pub use header::{checksum, Header};
+pub use instruction::Instruction;
+pub use io::ZIO;
+pub use machine::{Fault, Machine, StepResult};
+pub use memory::{Memory, MemoryError, Protection};
+pub use object::{Object, Property};
+pub use profiler::ProfileFormat;
+pub use routine::Routine;
+pub use snapshot::Snapshot;
+pub use zil::compile;
+pub use zstring::ZString;
@@ -0,0 +1,1146 @@
+use backtrace::Backtrace;
+use coverage::Coverage;
+use dictionary::Dictionary;
+use header::{checksum, Header};
+use history::{Change, History, Step};
+use instruction::{Instruction, Operand, Return};
+use io::ZIO;
+use memory::{Frame, Memory, MemoryError, Protection};
+use meta::date;
+use object::{Object, Property};
+use opcode::Opcode;
+use profiler::{ProfileFormat, Profiler};
+use quetzal;
+use rand::rngs::SmallRng;
+use rand::{FromEntropy, RngCore, SeedableRng};
+use snapshot::{compress, decompress, Snapshot};
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use zstring::ZString;
+
+// An error the game can't carry on from: what went wrong, the address of the instruction that
+// caused it and the call stack at the time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub message: String,
+    pub pc: usize,
+    pub backtrace: Backtrace,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n\n{}", self.message, self.backtrace)
+    }
+}
+
+// Why step handed control back to the host. Faults carry where they happened and the backtrace
+// at that point.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    // The game wants a line of up to max_length characters. Call step again once ZIO has one.
+    NeedsLine { max_length: usize },
+    // The game wants a single key press. Only later versions of the Z-machine ask for one.
+    NeedsChar,
+    // The game has ended.
+    Quit,
+    // The game asked to start again. Call restart and then step.
+    Restart,
+    Error(Fault),
+    // The instruction budget ran out. Call step again to carry on.
+    Yielded,
+}
+
+pub(crate) enum MachineState {
+    Continue,
+    // The size of the text buffer.
+    GetInput(usize),
+    Break(String),
+    CleanExit,
+    Restart,
+}
+
+pub struct Machine {
+    pub(crate) memory: Memory,
+    pub(crate) header: Header,
+    pub(crate) dictionary: Dictionary,
+    pub(crate) ip: usize,
+    // The checksum of the story as loaded, for VERIFY.
+    pub(crate) checksum: u16,
+    pub(crate) io: ZIO,
+    // The result step keeps returning once the game has quit or failed.
+    stopped: Option<StepResult>,
+    // Dynamic memory as loaded, for restarting.
+    pub(crate) initial: Arc<Vec<u8>>,
+    rng: SmallRng,
+    // Decoded instructions by address from the end of dynamic memory, filled in as they run.
+    cache: Vec<Option<Arc<Instruction>>>,
+    // Whether instructions are cached at all.
+    caching: bool,
+    instructions: u64,
+    // A fault raised while executing the current instruction, which stops the machine once the
+    // instruction is done.
+    fault: Option<String>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    // Snapshots taken as each command was read, newest last, and how many to keep.
+    pub(crate) undo: VecDeque<Snapshot>,
+    undo_depth: usize,
+    // The command that takes back turns, optionally followed by how many.
+    pub(crate) undo_word: String,
+    // The save /save and /restore use when they aren't given one.
+    pub(crate) save_name: String,
+    // The file the game is saved to whenever it waits for a command.
+    autosave: Option<String>,
+    // What the last instructions changed, for running backwards.
+    history: Option<History>,
+}
+
+const UNDO_DEPTH: usize = 10;
+
+// A copy of the game that runs independently of the original. The profiler and coverage recorder
+// stay with the original, so they aren't written out twice, and the copy decodes instructions
+// again as it needs them rather than copying the cache.
+impl Clone for Machine {
+    fn clone(&self) -> Machine {
+        Machine {
+            memory: self.memory.clone(),
+            header: self.header,
+            dictionary: self.dictionary.clone(),
+            ip: self.ip,
+            checksum: self.checksum,
+            io: self.io.clone(),
+            stopped: self.stopped.clone(),
+            initial: self.initial.clone(),
+            rng: self.rng.clone(),
+            cache: Vec::new(),
+            caching: self.caching,
+            instructions: self.instructions,
+            fault: self.fault.clone(),
+            profiler: None,
+            coverage: None,
+            undo: self.undo.clone(),
+            undo_depth: self.undo_depth,
+            undo_word: self.undo_word.clone(),
+            save_name: self.save_name.clone(),
+            autosave: None,
+            history: self.history.clone(),
+        }
+    }
+}
+
+impl Machine {
+    pub fn new(memory: Memory, header: Header) -> Machine {
+        Machine {
+            ip: memory.read_u16(0x6) as usize,
+            checksum: checksum(&memory, &header),
+            dictionary: Dictionary::new(&memory, memory.read_u16(0x08) as usize),
+            initial: Arc::new(memory.memory[..cmp::min(header.dynamic_end, memory.len())].to_vec()),
+            memory,
+            header,
+            io: ZIO::new(),
+            stopped: None,
+            rng: SmallRng::from_entropy(),
+            cache: Vec::new(),
+            caching: true,
+            instructions: 0,
+            fault: None,
+            profiler: None,
+            coverage: None,
+            undo: VecDeque::new(),
+            undo_depth: UNDO_DEPTH,
+            undo_word: "/undo".to_string(),
+            save_name: "default".to_string(),
+            autosave: None,
+            history: None,
+        }
+    }
+
+    pub fn io(&mut self) -> &mut ZIO {
+        &mut self.io
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = SmallRng::seed_from_u64(seed);
+    }
+
+    // Number of instructions executed so far.
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    // Turns the cache of decoded instructions on or off. It is on by default; turning it off is
+    // only useful to measure what it saves.
+    pub fn cache_instructions(&mut self, on: bool) {
+        self.caching = on;
+        self.cache = Vec::new();
+    }
+
+    // What to do when the game reads past the end of memory or writes outside dynamic memory.
+    // Strict by default.
+    pub fn protect(&mut self, protection: Protection) {
+        self.memory.protection = protection;
+    }
+
+    // How many turns the player can take back, 10 by default. 0 turns undo off.
+    pub fn keep_undo(&mut self, depth: usize) {
+        self.undo_depth = depth;
+        while self.undo.len() > depth {
+            self.undo.pop_front();
+        }
+    }
+
+    // The command the player types to take back a turn, or "/undo 3" for three. It is read before
+    // the game sees the command.
+    pub fn undo_word(&mut self, word: &str) {
+        self.undo_word = word.to_lowercase();
+    }
+
+    // Saves the game to path every time it waits for a command, so that however the player stops,
+    // the game can be restored as it was at the last prompt. The save is removed when the game
+    // quits.
+    pub fn autosave(&mut self, path: Option<String>) {
+        self.autosave = path;
+    }
+
+    pub fn profile(&mut self, format: ProfileFormat, output: Option<String>) {
+        self.profiler = Some(Profiler::new(format, output));
+    }
+
+    pub fn record_coverage(&mut self, output: String) {
+        self.coverage = Some(Coverage::new(output));
+    }
+
+    // Records what the last instructions changed, up to the number given, so the machine can be
+    // stepped backwards. 0 stops recording.
+    pub fn record_history(&mut self, instructions: usize) {
+        self.history = match instructions {
+            0 => None,
+            x => Some(History::new(x)),
+        };
+    }
+
+    pub fn backtrace(&self) -> Backtrace {
+        let main = (self.memory.read_u16(0x6) as usize).saturating_sub(1);
+        Backtrace::new(&self.memory, main, self.ip)
+    }
+
+    fn write_local(&mut self, var: u8, val: u16) {
+        if let Some(frame) = self.memory.frames.last() {
+            if (var as usize) < frame.num_locals {
+                let index = frame.stack_start + (var as usize);
+                self.memory.set_stack(index, val);
+                return;
+            }
+        }
+        self.fault = Some(format!("routine has no local {}", var));
+    }
+
+    // Reports a bad access by the game, and returns whether it went ahead.
+    fn memory_error(&mut self, e: MemoryError) -> bool {
+        match self.memory.protection {
+            Protection::Strict => {
+                self.fault = Some(e.to_string());
+                false
+            }
+            Protection::Warn => {
+                self.io.log(&format!("Warning: {}", e));
+                true
+            }
+            Protection::Permissive => true,
+        }
+    }
+
+    // Whether a string starting at offset can be read.
+    fn check_read(&mut self, offset: usize) -> bool {
+        match self.memory.try_read_u16(offset) {
+            Ok(_) => true,
+            Err(e) => self.memory_error(e),
+        }
+    }
+
+    fn load_u8(&mut self, offset: usize) -> u8 {
+        match self.memory.try_read_u8(offset) {
+            Ok(x) => x,
+            Err(e) => {
+                self.memory_error(e);
+                0
+            }
+        }
+    }
+
+    fn load_u16(&mut self, offset: usize) -> u16 {
+        match self.memory.try_read_u16(offset) {
+            Ok(x) => x,
+            Err(e) => {
+                self.memory_error(e);
+                0
+            }
+        }
+    }
+
+    // Reports the writes that broke protection during the last instruction. Those that went ahead
+    // may have changed code.
+    fn check_writes(&mut self) {
+        for (e, offset, length) in std::mem::take(&mut self.memory.bad_writes) {
+            if self.memory_error(e) {
+                self.invalidate(offset, length);
+            }
+        }
+    }
+
+    fn write_global(&mut self, var: u8, val: u16) {
+        let index = var as usize * 2;
+        let offset = self.header.globals + self.header.dynamic_start + index;
+        self.memory.store_u16(offset, val);
+    }
+
+    fn write_var(&mut self, var: Return, val: u16) {
+        match var {
+            Return::Variable(x) => match x {
+                x if x >= 0x10 => self.write_global(x - 0x10, val),
+                0 => self.memory.push(val),
+                _ => self.write_local(x - 1, val),
+            },
+            Return::Indirect(x) => match x {
+                x if x >= 0x10 => self.write_global(x - 0x10, val),
+                0 => {
+                    self.pop();
+                    self.memory.push(val)
+                }
+                _ => self.write_local(x - 1, val),
+            },
+            _ => {}
+        }
+    }
+
+    fn read_local(&mut self, var: u8) -> u16 {
+        if let Some(frame) = self.memory.frames.last() {
+            if (var as usize) < frame.num_locals {
+                return self.memory.stack[frame.stack_start + (var as usize)];
+            }
+        }
+        self.fault = Some(format!("routine has no local {}", var));
+        0
+    }
+
+    // Where the current routine's evaluation stack starts, above its locals.
+    fn stack_base(&self) -> usize {
+        self.memory
+            .frames
+            .last()
+            .map_or(0, |f| f.stack_start + f.num_locals)
+    }
+
+    fn pop(&mut self) -> u16 {
+        if self.memory.stack.len() > self.stack_base() {
+            return self.memory.pop().unwrap_or(0);
+        }
+        self.fault = Some("stack underflow".to_string());
+        0
+    }
+
+    fn peek(&mut self) -> u16 {
+        if self.memory.stack.len() > self.stack_base() {
+            return self.memory.stack.last().cloned().unwrap_or(0);
+        }
+        self.fault = Some("stack underflow".to_string());
+        0
+    }
+
+    fn read_global(&mut self, var: u8) -> u16 {
+        let index = var as usize * 2;
+        let offset = self.header.globals + self.header.dynamic_start + index;
+        self.load_u16(offset)
+    }
+
+    fn read_var(&mut self, var: Operand) -> u16 {
+        match var {
+            Operand::Variable(x) => match x {
+                x if x >= 0x10 => self.read_global(x - 0x10),
+                0 => self.pop(),
+                _ => self.read_local(x - 1),
+            },
+            Operand::Indirect(x) => match x {
+                x if x >= 0x10 => self.read_global(x - 0x10),
+                0 => self.peek(),
+                _ => self.read_local(x - 1),
+            },
+            Operand::Large(x) => x,
+            Operand::Small(x) => x as u16,
+            Operand::Omitted => 0,
+        }
+    }
+
+    fn call(&mut self, i: &Instruction) {
+        let addr = self.header.dynamic_start + (self.read_var(i.arg(0)) as usize) * 2;
+        let ret_addr = self.ip + i.length;
+        let mut args = [0u16; 3];
+        for (n, &a) in i.args.iter().skip(1).enumerate() {
+            args[n] = self.read_var(a);
+        }
+        let num_args = i.args.len().saturating_sub(1);
+        if addr - self.header.dynamic_start == 0 {
+            self.write_var(i.ret, 0);
+            self.ip = ret_addr;
+        } else {
+            let num_locals = self.load_u8(addr) as usize;
+            self.memory.push_frame(Frame {
+                addr,
+                stack_start: self.memory.stack.len(),
+                num_locals,
+                return_storage: i.ret,
+                return_addr: ret_addr,
+            });
+            for i in 0..num_locals {
+                let arg = match args[..num_args].get(i) {
+                    Some(&arg) => arg,
+                    None => self.load_u16(addr + 1 + i * 2),
+                };
+                self.memory.push(arg);
+            }
+            self.ip = addr + 1 + num_locals * 2;
+        }
+    }
+
+    fn ret(&mut self, val: u16) {
+        let frame = match self.memory.pop_frame() {
+            Some(frame) => frame,
+            None => {
+                self.fault = Some("return from the main routine".to_string());
+                return;
+            }
+        };
+        while self.memory.stack.len() != frame.stack_start {
+            self.memory.pop();
+        }
+        self.write_var(frame.return_storage, val);
+        self.ip = frame.return_addr;
+    }
+
+    fn jump(&mut self, i: &Instruction, compare: bool) {
+        if let Some(x) = i.compare {
+            if compare == x {
+                self.ip = match i.jump_offset {
+                    Some(0) => {
+                        self.ret(0);
+                        self.ip
+                    }
+                    Some(1) => {
+                        self.ret(1);
+                        self.ip
+                    }
+                    Some(x) => {
+                        let offset = (i.offset + i.length) as i32 + x - 2;
+                        offset as usize
+                    }
+                    None => self.ip,
+                };
+            }
+        }
+    }
+
+    // Forgets the cached instructions that a write to static or high memory changed. Games only
+    // get to make one when protection lets them.
+    fn invalidate(&mut self, offset: usize, length: usize) {
+        if offset + length <= self.header.dynamic_end {
+            return;
+        }
+        for entry in self.cache.iter_mut() {
+            if let Some(ref i) = *entry {
+                if i.offset < offset + length && offset < i.offset + i.length {
+                    *entry = None;
+                }
+            }
+        }
+    }
+
+    // Static and high memory rarely change, so instructions there are decoded once and cached.
+    fn decode(&mut self) -> Arc<Instruction> {
+        if !self.caching || self.ip < self.header.dynamic_end {
+            return Arc::new(Instruction::new(&self.memory, self.ip));
+        }
+        if self.cache.is_empty() {
+            self.cache = vec![None; self.memory.len() - self.header.dynamic_end];
+        }
+        let index = self.ip - self.header.dynamic_end;
+        if let Some(ref i) = self.cache[index] {
+            return i.clone();
+        }
+        let i = Arc::new(Instruction::new(&self.memory, self.ip));
+        self.cache[index] = Some(i.clone());
+        i
+    }
+
+    fn execute(&mut self, i: &Instruction) -> MachineState {
+        macro_rules! address {
+            ($e:expr) => {
+                self.header.dynamic_start + $e
+            };
+        }
+        macro_rules! packed_address {
+            ($e:expr) => {
+                self.header.dynamic_start + 2 * $e
+            };
+        }
+        macro_rules! convert_arg {
+            ($e:expr, Object) => (
+                {
+                    let x = $e as usize;
+                    if x == 0 {
+                        return MachineState::Break("attempted to access object 0".to_string());
+                    }
+                    Object::new(&self.memory, x)
+                }
+            );
+            ($e:expr, Variable) => (
+                match i.arg(0) {
+                    Operand::Large(x) => x as u8,
+                    Operand::Small(x) => x,
+                    Operand::Variable(_) => self.read_var(i.arg(0)) as u8,
+                    _ => 0,
+                }
+            );
+            ($e:expr, $type:tt) => (
+                $e as $type
+            );
+        }
+        macro_rules! read_args {
+            ($arg1_type:tt, $arg2_type:tt, $arg3_type:tt) => {
+                (
+                    convert_arg!(self.read_var(i.arg(0)), $arg1_type),
+                    convert_arg!(self.read_var(i.arg(1)), $arg2_type),
+                    convert_arg!(self.read_var(i.arg(2)), $arg3_type),
+                )
+            };
+            ($arg1_type:tt, $arg2_type:tt) => {
+                (
+                    convert_arg!(self.read_var(i.arg(0)), $arg1_type),
+                    convert_arg!(self.read_var(i.arg(1)), $arg2_type),
+                )
+            };
+            ($arg1_type:tt) => {
+                convert_arg!(self.read_var(i.arg(0)), $arg1_type)
+            };
+        }
+
+        let oldip = self.ip;
+        let length = i.length;
+        match i.op {
+            Opcode::Call => {
+                self.call(i);
+            }
+            Opcode::Add => {
+                let (x, y) = read_args!(i32, i32);
+                self.write_var(i.ret, ((x + y) % 0x10000) as u16);
+            }
+            Opcode::Je => {
+                let x = read_args!(u16);
+                let compare = (1..i.args.len()).any(|n| x == self.read_var(i.arg(n)));
+                self.jump(i, compare);
+            }
+            Opcode::Sub => {
+                let (x, y) = read_args!(i32, i32);
+                self.write_var(i.ret, ((x - y) % 0x10000) as u16);
+            }
+            Opcode::Jz => {
+                let x = read_args!(u16);
+                self.jump(i, x == 0);
+            }
+            Opcode::Storew => {
+                let (x, y, val) = read_args!(usize, usize, u16);
+                let addr = x + 2 * y;
+                self.memory.store_u16(address!(addr), val);
+            }
+            Opcode::Ret => {
+                let val = read_args!(u16);
+                self.ret(val);
+            }
+            Opcode::Loadw => {
+                let (x, y) = read_args!(usize, usize);
+                let addr = x + 2 * y;
+                let val = self.load_u16(address!(addr));
+                self.write_var(i.ret, val);
+            }
+            Opcode::Jump => {
+                let x = read_args!(i16);
+                self.ip = (self.ip as i32 + i.length as i32 + x as i32 - 2) as usize;
+            }
+            Opcode::PutProp => {
+                let (obj, y, val) = read_args!(Object, usize, u16);
+                if y == 0 || y > 31 {
+                    return MachineState::Break("property outside allowed range".to_string());
+                }
+                let prop = obj.get_property(&self.memory, address!(y));
+                prop.write(&mut self.memory, val);
+            }
+            Opcode::Store => {
+                let (x, y) = read_args!(Variable, u16);
+                self.write_var(Return::Indirect(x), y);
+            }
+            Opcode::TestAttr => {
+                let (obj, y) = read_args!(Object, usize);
+                if y > 31 {
+                    return MachineState::Break("attribute outside allowed range".to_string());
+                }
+                self.jump(i, obj.test_attr(&self.memory, y));
+            }
+            Opcode::Print => {
+                if let Some(ref s) = i.string {
+                    self.io.print(&format!("{}", s));
+                    let _ = self.io.flush();
+                }
+            }
+            Opcode::NewLine => {
+                self.io.print("\n");
+            }
+            Opcode::Loadb => {
+                let (x, y) = read_args!(usize, usize);
+                let val = self.load_u8(address!(x + y)) as u16;
+                self.write_var(i.ret, val);
+            }
+            Opcode::And => {
+                let (x, y) = read_args!(u16, u16);
+                self.write_var(i.ret, x & y);
+            }
+            Opcode::PrintNum => {
+                let x = read_args!(i16);
+                self.io.print(&format!("{}", x));
+            }
+            Opcode::IncChk => {
+                let (x, y) = read_args!(Variable, i16);
+                let old = self.read_var(Operand::Variable(x)) as i16;
+                self.write_var(Return::Variable(x), old.wrapping_add(1) as u16);
+                self.jump(i, old.wrapping_add(1) > y);
+            }
+            Opcode::PrintChar => {
+                let x = read_args!(u8);
+                self.io.print(&(x as char).to_string());
+            }
+            Opcode::Rtrue => {
+                self.ret(1);
+            }
+            Opcode::InsertObj => {
+                let (obj, dest) = read_args!(Object, Object);
+                obj.insert(&mut self.memory, &dest);
+            }
+            Opcode::Push => {
+                let x = read_args!(u16);
+                self.write_var(Return::Variable(0), x);
+            }
+            Opcode::Pop => {
+                self.read_var(Operand::Variable(0));
+            }
+            Opcode::Pull => {
+                let x = read_args!(Variable);
+                let val = self.read_var(Operand::Variable(0));
+                self.write_var(Return::Indirect(x), val);
+            }
+            Opcode::SetAttr => {
+                let (obj, y) = read_args!(Object, usize);
+                if y > 31 {
+                    return MachineState::Break("attribute outside allowed range".to_string());
+                }
+                obj.set_attr(&mut self.memory, y, true);
+            }
+            Opcode::Jin => {
+                let (obj, y) = read_args!(Object, usize);
+                self.jump(i, obj.parent(&self.memory) == y);
+            }
+            Opcode::PrintObj => {
+                let obj = read_args!(Object);
+                let name = obj.name(&self.memory);
+                self.io.print(&format!("{}", name));
+            }
+            Opcode::GetParent => {
+                let obj = read_args!(Object);
+                let parent = obj.parent(&self.memory);
+                self.write_var(i.ret, parent as u16);
+            }
+            Opcode::GetProp => {
+                let (obj, y) = read_args!(Object, usize);
+                if y == 0 || y > 31 {
+                    return MachineState::Break("property outside allowed range".to_string());
+                }
+                let prop = obj.get_property(&self.memory, y);
+                let val = prop.read(&self.memory);
+                self.write_var(i.ret, val);
+            }
+            Opcode::Jg => {
+                let (x, y) = read_args!(i16, i16);
+                self.jump(i, x > y);
+            }
+            Opcode::GetChild => {
+                let obj = read_args!(Object);
+                let child = obj.child(&self.memory);
+                self.write_var(i.ret, child as u16);
+                self.jump(i, child != 0);
+            }
+            Opcode::GetSibling => {
+                let obj = read_args!(Object);
+                let sibling = obj.sibling(&self.memory);
+                self.write_var(i.ret, sibling as u16);
+                self.jump(i, sibling != 0);
+            }
+            Opcode::Rfalse => {
+                self.ret(0);
+            }
+            Opcode::Inc => {
+                let x = read_args!(Variable);
+                let old = self.read_var(Operand::Variable(x)) as i32;
+                self.write_var(Return::Variable(x), ((old + 1) % 0x10000) as u16);
+            }
+            Opcode::Jl => {
+                let (x, y) = read_args!(i16, i16);
+                self.jump(i, x < y);
+            }
+            Opcode::RetPopped => {
+                let x = self.read_var(Operand::Variable(0));
+                self.ret(x);
+            }
+            Opcode::Sread => {
+                if let Some(path) = self.autosave.clone() {
+                    let data = self.save_game();
+                    if let Err(e) = self.io.autosave(&path, &data) {
+                        self.io
+                            .log(&format!("Warning: can't autosave to {}: {}", path, e));
+                        self.autosave = None;
+                    }
+                }
+                if !self.io.poll_input() {
+                    // The instruction runs again once there is input, so the stack can't be popped.
+                    let text = match i.arg(0) {
+                        Operand::Variable(x) => Operand::Indirect(x),
+                        x => x,
+                    };
+                    let text = address!(self.read_var(text) as usize);
+                    return MachineState::GetInput(self.load_u8(text) as usize);
+                }
+                let input = self.io.input();
+                // Returning here leaves the PC alone, so the read runs again. Meta-commands see
+                // the input as typed, so names keep their case.
+                if let Some(state) = self.meta_command(input.trim()) {
+                    return state;
+                }
+                let input = input.trim().to_lowercase();
+                self.push_undo();
+
+                let x = address!(self.read_var(i.arg(0)) as usize);
+                let y = address!(self.read_var(i.arg(1)) as usize);
+                let max_length = std::cmp::min(self.load_u8(x) as usize, input.len());
+
+                for (i, c) in input.bytes().take(max_length).enumerate() {
+                    self.memory.store_u8(x + 1 + i, c);
+                }
+                self.memory.store_u8(x + max_length + 1, 0);
+
+                let tokens: Vec<_> = input
+                    .split(|c| c == ' ' || self.dictionary.separators.contains(&c))
+                    .collect();
+                let max_parse = std::cmp::min(self.load_u8(y) as usize, tokens.len());
+                self.memory.store_u8(y + 1, max_parse as u8);
+                for (i, token) in tokens[..max_parse].iter().enumerate() {
+                    let offset = y + 2 + 4 * i;
+                    if let Some(zs) = self.dictionary.get_word(token) {
+                        self.memory.store_u16(offset, zs.offset as u16);
+                    } else {
+                        self.memory.store_u16(offset, 0);
+                    }
+                    self.memory.store_u8(offset + 2, token.len() as u8);
+                    let index = input.find(token).unwrap();
+                    self.memory.store_u8(offset + 3, (index + 1) as u8);
+                }
+            }
+            Opcode::DecChk => {
+                let (x, y) = read_args!(Variable, i16);
+                let old = self.read_var(Operand::Variable(x)) as i16;
+                self.write_var(Return::Variable(x), old.wrapping_sub(1) as u16);
+                self.jump(i, old.wrapping_sub(1) < y);
+            }
+            Opcode::Mul => {
+                let (x, y) = read_args!(i64, i64);
+                self.write_var(i.ret, ((x * y) % 0x10000) as u16);
+            }
+            Opcode::Test => {
+                let (x, y) = read_args!(u16, u16);
+                self.jump(i, (x & y) == y);
+            }
+            Opcode::Storeb => {
+                let (x, y, val) = read_args!(usize, usize, u8);
+                self.memory.store_u8(address!(x + y), val);
+            }
+            Opcode::ClearAttr => {
+                let (obj, y) = read_args!(Object, usize);
+                if y > 31 {
+                    return MachineState::Break("attribute outside allowed range".to_string());
+                }
+                obj.set_attr(&mut self.memory, y, false);
+            }
+            Opcode::GetPropAddr => {
+                let (obj, y) = read_args!(Object, usize);
+                if let Some(prop) = obj.get_property_opt(&self.memory, y) {
+                    self.write_var(i.ret, prop.offset as u16 + 1);
+                } else {
+                    self.write_var(i.ret, 0);
+                }
+            }
+            Opcode::GetPropLen => {
+                let x = read_args!(usize);
+                if x == 0 {
+                    self.write_var(i.ret, 0);
+                } else {
+                    let property = Property::new(&self.memory, x - 1);
+                    self.write_var(i.ret, property.length as u16);
+                }
+            }
+            Opcode::PrintPaddr => {
+                let x = read_args!(usize);
+                if self.check_read(packed_address!(x)) {
+                    let zs = ZString::new(&self.memory, packed_address!(x));
+                    self.io.print(&format!("{}", zs));
+                }
+            }
+            Opcode::Dec => {
+                let x = read_args!(Variable);
+                let old = self.read_var(Operand::Variable(x)) as i32;
+                self.write_var(Return::Variable(x), ((old - 1) % 0x10000) as u16);
+            }
+            Opcode::PrintRet => {
+                if let Some(ref s) = i.string {
+                    self.io.print(&format!("{}\n", s));
+                }
+                self.ret(1);
+            }
+            Opcode::Div => {
+                let (x, y) = read_args!(i16, i16);
+                if y == 0 {
+                    return MachineState::Break("divide by zero".to_string());
+                }
+                self.write_var(i.ret, x.wrapping_div(y) as u16);
+            }
+            Opcode::PrintAddr => {
+                let x = read_args!(usize);
+                if self.check_read(address!(x)) {
+                    let zs = ZString::new(&self.memory, address!(x));
+                    self.io.print(&format!("{}", zs));
+                }
+            }
+            Opcode::Not => {
+                let x = read_args!(u16);
+                self.write_var(i.ret, !x);
+            }
+            Opcode::Or => {
+                let (x, y) = read_args!(u16, u16);
+                self.write_var(i.ret, x | y);
+            }
+            Opcode::Mod => {
+                let (x, y) = read_args!(i16, i16);
+                if y == 0 {
+                    return MachineState::Break("divide by zero".to_string());
+                }
+                self.write_var(i.ret, x.wrapping_rem(y) as u16);
+            }
+            Opcode::RemoveObj => {
+                let obj = read_args!(Object);
+                obj.remove(&mut self.memory);
+            }
+            Opcode::Random => {
+                let range = read_args!(i16);
+                if range <= 0 {
+                    self.rng = SmallRng::seed_from_u64(range as u64);
+                    self.write_var(i.ret, 0);
+                } else {
+                    let x = self.rng.next_u32() as u16;
+                    let val = x % range as u16 + 1;
+                    self.write_var(i.ret, val);
+                }
+            }
+            Opcode::GetNextProp => {
+                let (obj, y) = read_args!(Object, usize);
+                if let Some(index) = obj.get_next_property(&self.memory, y) {
+                    self.write_var(i.ret, index as u16);
+                } else {
+                    return MachineState::Break("could not find property".to_string());
+                }
+            }
+            Opcode::Load => {
+                let x = read_args!(Variable);
+                let val = self.read_var(Operand::Indirect(x));
+                self.write_var(i.ret, val);
+            }
+            Opcode::Verify => {
+                let verified = self.verify();
+                self.jump(i, verified);
+            }
+            Opcode::Quit => {
+                return MachineState::CleanExit;
+            }
+            Opcode::Restart => {
+                return MachineState::Restart;
+            }
+            _ => return MachineState::Break(format!("unimplemented instruction:\n{}", i)),
+        }
+        if self.ip == oldip {
+            self.ip += length;
+        }
+        MachineState::Continue
+    }
+
+    // Runs until the game needs input, stops or asks to restart, or until it has executed budget
+    // instructions. Once the game has quit or failed, returns the same result again.
+    pub fn step(&mut self, budget: Option<u64>) -> StepResult {
+        if let Some(ref result) = self.stopped {
+            return result.clone();
+        }
+        let start = self.instructions;
+        loop {
+            if self.ip >= self.memory.len() {
+                let s = format!("jumped outside memory to {:08X}", self.ip);
+                return self.stop(s);
+            }
+            if budget.is_some_and(|x| self.instructions - start >= x) {
+                return StepResult::Yielded;
+            }
+            let i = self.decode();
+            #[cfg(debug_assertions)]
+            self.io.trace(&format!("{}", i));
+            let name = i.name();
+            let op = i.op;
+            let depth = self.memory.frames.len();
+            if let Some(ref mut profiler) = self.profiler {
+                profiler.record(name, self.memory.frames.last().map_or(0, |f| f.addr));
+            }
+            if let Some(ref mut coverage) = self.coverage {
+                coverage.record(self.ip);
+            }
+            self.instructions += 1;
+            let ip = self.ip;
+            let rng = match (&self.history, op) {
+                (None, _) => None,
+                (Some(_), Opcode::Random | Opcode::Sread) => Some(self.rng.clone()),
+                (Some(_), _) => None,
+            };
+            if self.history.is_some() {
+                self.memory.journal = Some(Vec::new());
+            }
+            let mut state = self.execute(&i);
+            self.check_writes();
+            if let (Some(history), Some(changes)) = (&mut self.history, self.memory.journal.take())
+            {
+                history.record(Step { ip, rng, changes });
+            }
+            if let Some(s) = self.fault.take() {
+                self.ip = ip;
+                state = MachineState::Break(s);
+            }
+            if let Some(ref mut profiler) = self.profiler {
+                if self.memory.frames.len() > depth {
+                    profiler.record_call(self.memory.frames.last().unwrap().addr);
+                }
+                if let (Opcode::Sread, &MachineState::Continue) = (op, &state) {
+                    profiler.end_turn();
+                }
+            }
+            match state {
+                MachineState::Continue => {}
+                MachineState::Break(s) => return self.stop(s),
+                MachineState::GetInput(max_length) => return StepResult::NeedsLine { max_length },
+                MachineState::CleanExit => {
+                    // The game is over, so there's nothing to offer to resume next time.
+                    if let Some(path) = self.autosave.take() {
+                        self.io.forget_autosave(&path);
+                    }
+                    self.stopped = Some(StepResult::Quit);
+                    return StepResult::Quit;
+                }
+                MachineState::Restart => return StepResult::Restart,
+            }
+        }
+    }
+
+    fn stop(&mut self, message: String) -> StepResult {
+        let result = StepResult::Error(Fault {
+            message,
+            pc: self.ip,
+            backtrace: self.backtrace(),
+        });
+        self.stopped = Some(result.clone());
+        result
+    }
+
+    // Puts the game back as it was loaded, as RESTART does. Only the transcript and fixed-pitch
+    // bits of flags 2 are kept.
+    pub fn restart(&mut self) {
+        let flags = self.memory.read_u16(0x10) & 0x3;
+        let len = self.initial.len();
+        self.memory.memory[..len].copy_from_slice(&self.initial);
+        let restored = self.memory.read_u16(0x10);
+        self.memory.write_u16(0x10, (restored & !0x3) | flags);
+        self.memory.stack.clear();
+        self.memory.frames.clear();
+        self.ip = self.memory.read_u16(0x6) as usize;
+        self.stopped = None;
+        self.forget_history();
+    }
+
+    // Remembers the game as it is for undo.
+    pub(crate) fn push_undo(&mut self) {
+        if self.undo_depth > 0 {
+            let snapshot = self.snapshot();
+            self.remember(snapshot);
+        }
+    }
+
+    // Keeps a snapshot for undo, forgetting the oldest turn if there are too many.
+    pub(crate) fn remember(&mut self, snapshot: Snapshot) {
+        if self.undo_depth == 0 {
+            return;
+        }
+        if self.undo.len() == self.undo_depth {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(snapshot);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: compress(&self.initial, &self.memory.memory[..self.initial.len()]),
+            stack: self.memory.stack.clone(),
+            frames: self.memory.frames.clone(),
+            ip: self.ip,
+            rng: self.rng.clone(),
+            instructions: self.instructions,
+        }
+    }
+
+    // Puts the game back as it was when the snapshot was taken. Fails, leaving the game alone, if
+    // the snapshot wasn't taken of this story.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        let memory = decompress(&self.initial, &snapshot.memory)
+            .ok_or("the snapshot doesn't match the story")?;
+        self.memory.memory[..memory.len()].copy_from_slice(&memory);
+        self.memory.stack = snapshot.stack.clone();
+        self.memory.frames = snapshot.frames.clone();
+        self.ip = snapshot.ip;
+        self.rng = snapshot.rng.clone();
+        self.instructions = snapshot.instructions;
+        self.stopped = None;
+        self.fault = None;
+        self.forget_history();
+        Ok(())
+    }
+
+    // Jumps in the game can't be taken back, so history starts again after them. Dropping the
+    // journal keeps the instruction that made the jump, such as a restore, from being recorded.
+    fn forget_history(&mut self) {
+        if let Some(ref mut history) = self.history {
+            history.steps.clear();
+        }
+        self.memory.journal = None;
+    }
+
+    // The number of instructions recorded that can be taken back.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |x| x.steps.len())
+    }
+
+    // Takes back the last instruction recorded, leaving the machine about to run it again, and
+    // returns whether there was one. A game that has quit or failed can carry on afterwards.
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.history.as_mut().and_then(|x| x.steps.pop_back()) {
+            Some(x) => x,
+            None => return false,
+        };
+        self.memory.revert(&step.changes);
+        for change in step.changes.iter() {
+            if let Change::Byte(offset, _) = *change {
+                self.invalidate(offset, 1);
+            }
+        }
+        self.ip = step.ip;
+        if let Some(rng) = step.rng {
+            self.rng = rng;
+        }
+        self.instructions -= 1;
+        self.stopped = None;
+        self.fault = None;
+        true
+    }
+
+    // Runs back to just before the last recorded instruction that wrote any of the length bytes
+    // at addr, and returns how many instructions were taken back. If none of them wrote there,
+    // returns None without moving.
+    pub fn run_back_to_write(&mut self, addr: usize, length: usize) -> Option<usize> {
+        let steps = &self.history.as_ref()?.steps;
+        let back = steps.iter().rev().position(|x| x.wrote(addr, length))? + 1;
+        for _ in 0..back {
+            self.step_back();
+        }
+        Some(back)
+    }
+
+    // Identifies the story by its release, serial number and checksum, for naming its saves.
+    pub fn story_key(&self) -> String {
+        let id = quetzal::identity(&self.memory);
+        let serial: String = id[2..8]
+            .iter()
+            .map(|&x| match x {
+                b'0'..=b'9' | b'A'..=b'Z' | b'a'..=b'z' => x as char,
+                _ => '_',
+            })
+            .collect();
+        format!(
+            "{}-{}-{:02x}{:02x}",
+            (id[0] as u16) << 8 | id[1] as u16,
+            serial,
+            id[8],
+            id[9]
+        )
+    }
+
+    // The game as a Quetzal save file, noting the status line and the time. Saves made while the
+    // game waits for a command restore to the same wait.
+    pub fn save_game(&self) -> Vec<u8> {
+        let note = format!("{} Saved {}.", self.status(), date(self.io.now()));
+        quetzal::write(&quetzal::identity(&self.memory), &self.snapshot(), &note)
+    }
+
+    // Restores a Quetzal save of this story. The transcript and fixed-pitch bits of flags 2 are
+    // kept, as for restart.
+    pub fn restore_game(&mut self, data: &[u8]) -> Result<(), String> {
+        let snapshot = quetzal::read(
+            data,
+            &quetzal::identity(&self.memory),
+            &self.memory,
+            &self.initial,
+            self.rng.clone(),
+            self.instructions,
+        )?;
+        let flags = self.memory.read_u16(0x10) & 0x3;
+        self.restore_snapshot(&snapshot)?;
+        let restored = self.memory.read_u16(0x10);
+        self.memory.write_u16(0x10, (restored & !0x3) | flags);
+        Ok(())
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(ref profiler) = self.profiler {
+            let report = profiler.report();
+            match profiler.output {
+                Some(ref path) => {
+                    if let Err(e) = std::fs::write(path, report) {
+                        self.io.log(&format!("Error writing profile: {}", e));
+                    }
+                }
+                None => eprint!("{}", report),
+            }
+        }
+        if let Some(ref coverage) = self.coverage {
+            let report = coverage.report(&self.memory, &self.header);
+            if let Err(e) = std::fs::write(&coverage.output, report) {
+                self.io.log(&format!("Error writing coverage: {}", e));
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use history::Change;
+use instruction::Return;
+use std::fmt;
+
+const HEADER_SIZE: usize = 0x40;
+// Flags 2 is the only part of a version 3 header the game may change.
+const WRITABLE_HEADER: [usize; 2] = [0x10, 0x11];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub(crate) addr: usize,
+    pub(crate) stack_start: usize,
+    pub(crate) num_locals: usize,
+    pub(crate) return_storage: Return,
+    pub(crate) return_addr: usize,
+}
+
+// How the machine treats a game reading past the end of memory or writing outside dynamic memory:
+// stop with an error, log a warning and carry on, or carry on silently.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protection {
+    Strict,
+    Warn,
+    Permissive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryError {
+    // The address and the length of memory.
+    OutOfBounds(usize, usize),
+    // A write to a header field the game must not change.
+    Header(usize),
+    // A write to static or high memory, and where dynamic memory ends.
+    ReadOnly(usize, usize),
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::OutOfBounds(addr, len) => {
+                write!(
+                    f,
+                    "address {:04x} is past the end of memory at {:04x}",
+                    addr, len
+                )
+            }
+            MemoryError::Header(addr) => write!(f, "write to read-only header field {:02x}", addr),
+            MemoryError::ReadOnly(addr, end) => write!(
+                f,
+                "write to {:04x}, outside dynamic memory which ends at {:04x}",
+                addr, end
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Memory {
+    pub(crate) memory: Vec<u8>,
+    pub(crate) dynamic_end: usize,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) frames: Vec<Frame>,
+    // The changes made so far by the instruction being executed, while history is being recorded.
+    pub(crate) journal: Option<Vec<Change>>,
+    pub(crate) protection: Protection,
+    // Writes by the game that broke protection, and the address and length of each, for the
+    // machine to report once the instruction is done.
+    pub(crate) bad_writes: Vec<(MemoryError, usize, usize)>,
+}
+
+impl Memory {
+    pub fn new(buffer: &[u8]) -> Memory {
+        Memory {
+            memory: Vec::from(buffer),
+            dynamic_end: ((buffer.get(0xe).cloned().unwrap_or(0) as usize) << 8)
+                | buffer.get(0xf).cloned().unwrap_or(0) as usize,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            journal: None,
+            protection: Protection::Strict,
+            bad_writes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    // Bytes past the end of the story read as zero and writes to them are dropped, so a broken
+    // or hostile story can't make the interpreter index out of bounds.
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.memory.get(offset).cloned().unwrap_or(0)
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        ((self.read_u8(offset) as u16) << 8) | (self.read_u8(offset.wrapping_add(1)) as u16)
+    }
+
+    pub fn write_u8(&mut self, offset: usize, val: u8) {
+        if let Some(x) = self.memory.get_mut(offset) {
+            if let Some(ref mut journal) = self.journal {
+                journal.push(Change::Byte(offset, *x));
+            }
+            *x = val;
+        }
+    }
+
+    pub fn write_u16(&mut self, offset: usize, val: u16) {
+        self.write_u8(offset, (val >> 8) as u8);
+        self.write_u8(offset.wrapping_add(1), (val & 0xff) as u8);
+    }
+
+    // Checked accesses for the addresses a game computes. Reads fail past the end of memory and
+    // writes outside dynamic memory, except to the header fields the game may change.
+    pub fn try_read_u8(&self, offset: usize) -> Result<u8, MemoryError> {
+        match self.memory.get(offset) {
+            Some(&x) => Ok(x),
+            None => Err(MemoryError::OutOfBounds(offset, self.len())),
+        }
+    }
+
+    pub fn try_read_u16(&self, offset: usize) -> Result<u16, MemoryError> {
+        match offset.checked_add(1) {
+            Some(x) if x < self.len() => Ok(self.read_u16(offset)),
+            _ => Err(MemoryError::OutOfBounds(offset, self.len())),
+        }
+    }
+
+    pub fn check_write(&self, offset: usize, length: usize) -> Result<(), MemoryError> {
+        for x in offset..offset.saturating_add(length) {
+            if x < HEADER_SIZE && !WRITABLE_HEADER.contains(&x) {
+                return Err(MemoryError::Header(x));
+            }
+            if x >= self.len() {
+                return Err(MemoryError::OutOfBounds(x, self.len()));
+            }
+            if x >= self.dynamic_end {
+                return Err(MemoryError::ReadOnly(x, self.dynamic_end));
+            }
+        }
+        Ok(())
+    }
+
+    // Checked writes for the addresses a game computes. A write that breaks protection is noted,
+    // and only goes ahead if protection isn't strict.
+    pub(crate) fn store_u8(&mut self, offset: usize, val: u8) {
+        if self.check_store(offset, 1) {
+            self.write_u8(offset, val);
+        }
+    }
+
+    pub(crate) fn store_u16(&mut self, offset: usize, val: u16) {
+        if self.check_store(offset, 2) {
+            self.write_u16(offset, val);
+        }
+    }
+
+    fn check_store(&mut self, offset: usize, length: usize) -> bool {
+        match self.check_write(offset, length) {
+            Ok(()) => true,
+            Err(e) => {
+                self.bad_writes.push((e, offset, length));
+                self.protection != Protection::Strict
+            }
+        }
+    }
+
+    // The stack and call frames are changed through these, so that history can take the changes
+    // back.
+    pub(crate) fn push(&mut self, val: u16) {
+        self.record(Change::Push);
+        self.stack.push(val);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<u16> {
+        let val = self.stack.pop()?;
+        self.record(Change::Pop(val));
+        Some(val)
+    }
+
+    pub(crate) fn set_stack(&mut self, index: usize, val: u16) {
+        self.record(Change::Stack(index, self.stack[index]));
+        self.stack[index] = val;
+    }
+
+    pub(crate) fn push_frame(&mut self, frame: Frame) {
+        self.record(Change::Call);
+        self.frames.push(frame);
+    }
+
+    pub(crate) fn pop_frame(&mut self) -> Option<Frame> {
+        let frame = self.frames.pop()?;
+        self.record(Change::Return(frame));
+        Some(frame)
+    }
+
+    fn record(&mut self, change: Change) {
+        if let Some(ref mut journal) = self.journal {
+            journal.push(change);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use instruction::Instruction;
+use machine::{Machine, MachineState};
+use object::Object;
+use quetzal;
+use std::cmp;
+
+const HELP: &str = "Commands for rustzork rather than the game:
+  /save [NAME]            save the game, under the last name saved or restored if none is given
+  /restore [NAME]         restore a saved game
+  /saves                  list the saved games
+  /delete NAME            delete a saved game
+  /undo [TURNS]           take back the last turn, or the given number of turns
+  /transcript on [FILE]   copy the game to a file, transcript.txt by default
+  /transcript off         stop copying the game
+  /seed N                 seed the random number generator
+  /status                 show the room, score and moves
+  /back [N]               show where the game was N instructions ago, with --history
+  /written ADDR           show the last instruction that wrote ADDR, in hex, or global Gnn
+  /quit                   stop playing
+  /help                   show this list
+";
+
+// Names of saves are kept to letters, digits, dashes and underscores, to be safe as file names.
+// Save files elsewhere can be looked at with rustzork save-info.
+fn valid_name(name: &str) -> bool {
+    name.chars()
+        .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+// Transcripts are written to the working directory, under a name like a save's with an
+// extension if wanted.
+fn valid_file_name(name: &str) -> bool {
+    !name.starts_with('.')
+        && name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, '-' | '_' | '.'))
+}
+
+// The date and time, in UTC, that many seconds after 1970.
+pub(crate) fn date(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+    // Howard Hinnant's civil_from_days.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60
+    )
+}
+
+// The turns asked for by the arguments of an undo command.
+fn undo_turns(args: &[&str]) -> Option<usize> {
+    match *args {
+        [] => Some(1),
+        [n] => n.parse().ok().filter(|&x| x > 0),
+        _ => None,
+    }
+}
+
+fn instructions_back(n: usize) -> String {
+    match n {
+        1 => "1 instruction".to_string(),
+        n => format!("{} instructions", n),
+    }
+}
+
+impl Machine {
+    // Carries out a command for rustzork instead of the game: one starting with a slash, or the
+    // undo word. Returns how the read it was typed at carries on, or None if the game should have
+    // the input. Replies are bracketed and followed by a prompt, since the read starts again.
+    pub(crate) fn meta_command(&mut self, input: &str) -> Option<MachineState> {
+        let mut words = input.split_whitespace();
+        let command = words.next()?.to_lowercase();
+        let command = command.as_str();
+        let args: Vec<&str> = words.collect();
+        let reply = if command == self.undo_word || command == "/undo" {
+            match undo_turns(&args) {
+                Some(turns) => self.undo(turns),
+                None if command.starts_with('/') => "Usage: /undo [TURNS]".to_string(),
+                None => return None,
+            }
+        } else if !command.starts_with('/') {
+            return None;
+        } else {
+            match (command, &args[..]) {
+                ("/save" | "/restore" | "/delete", [name]) if !valid_name(name) => {
+                    format!(
+                        "{} isn't a name for a save. Use letters, digits, - and _.",
+                        name
+                    )
+                }
+                ("/save", [] | [_]) => self.save(args.first().cloned()),
+                ("/restore", [] | [_]) => self.restore(args.first().cloned()),
+                ("/saves", []) => {
+                    let list = self.list_saves();
+                    self.io.print(&list);
+                    self.io.print("\n>");
+                    return Some(MachineState::Continue);
+                }
+                ("/delete", [name]) => match self.io.delete_slot(name) {
+                    Ok(()) => format!("Deleted {}.", name),
+                    Err(e) => format!("Couldn't delete {}: {}.", name, e),
+                },
+                ("/transcript", ["on"]) => self.transcript(Some("transcript.txt")),
+                ("/transcript", ["on", path]) if !valid_file_name(path) => format!(
+                    "{} isn't a file name. Use letters, digits, -, _ and a . for the extension.",
+                    path
+                ),
+                ("/transcript", ["on", path]) => self.transcript(Some(path)),
+                ("/transcript", ["off"]) => self.transcript(None),
+                ("/seed", [n]) => match n.parse() {
+                    Ok(x) => {
+                        self.seed(x);
+                        format!("Random numbers seeded with {}.", x)
+                    }
+                    Err(_) => format!("{} isn't a number.", n),
+                },
+                ("/status", []) => self.status(),
+                ("/quit", []) => return Some(MachineState::CleanExit),
+                ("/back", [] | [_]) => match args.first().map_or(Ok(1), |x| x.parse()) {
+                    Ok(n) if n > 0 => {
+                        self.look_back(n);
+                        return Some(MachineState::Continue);
+                    }
+                    _ => format!("{} isn't a number of instructions.", args[0]),
+                },
+                ("/written", [place]) => match self.place(place) {
+                    Some((addr, length)) => {
+                        self.last_write(place, addr, length);
+                        return Some(MachineState::Continue);
+                    }
+                    None => format!("{} isn't an address or a global.", place),
+                },
+                ("/help", []) => {
+                    self.io.print(HELP);
+                    self.io.print("\n>");
+                    return Some(MachineState::Continue);
+                }
+                (
+                    "/save" | "/restore" | "/saves" | "/delete" | "/transcript" | "/seed"
+                    | "/status" | "/back" | "/written" | "/quit",
+                    _,
+                ) => {
+                    format!(
+                        "{} doesn't take those arguments. Type /help for the list.",
+                        command
+                    )
+                }
+                _ => format!("There's no {} command. Type /help for the list.", command),
+            }
+        };
+        self.io.print(&format!("[{}]\n\n>", reply));
+        Some(MachineState::Continue)
+    }
+
+    // Rewinds to the snapshot taken the given number of commands ago, or as far back as there is.
+    // The machine is left at the read of that turn, waiting for a new command.
+    fn undo(&mut self, turns: usize) -> String {
+        let turns = cmp::min(turns, self.undo.len());
+        if turns == 0 {
+            return "Nothing to undo.".to_string();
+        }
+        let undone = self.undo.split_off(self.undo.len() - turns);
+        if let Err(e) = self.restore_snapshot(&undone[0]) {
+            return format!("Couldn't undo: {}.", e);
+        }
+        match turns {
+            1 => "Previous turn undone.".to_string(),
+            n => format!("{} turns undone.", n),
+        }
+    }
+
+    fn save(&mut self, name: Option<&str>) -> String {
+        let name = name.unwrap_or(&self.save_name).to_string();
+        let data = self.save_game();
+        match self.io.write_slot(&name, &data) {
+            Ok(()) => {
+                self.save_name = name;
+                format!("Saved {}.", self.save_name)
+            }
+            Err(e) => format!("Couldn't save {}: {}.", name, e),
+        }
+    }
+
+    // The game as it was before the restore can be got back with undo.
+    fn restore(&mut self, name: Option<&str>) -> String {
+        let name = name.unwrap_or(&self.save_name).to_string();
+        let before = self.snapshot();
+        match self.io.read_slot(&name).and_then(|x| self.restore_game(&x)) {
+            Ok(()) => {
+                self.remember(before);
+                self.save_name = name;
+                format!("Restored {}.", self.save_name)
+            }
+            Err(e) => format!("Couldn't restore {}: {}.", name, e),
+        }
+    }
+
+    // The named saves of this story, each with the status line and time it was saved at.
+    pub fn list_saves(&mut self) -> String {
+        let names = self.io.slots();
+        if names.is_empty() {
+            return "There are no saved games.\n".to_string();
+        }
+        let width = names.iter().map(|x| x.len()).max().unwrap_or(0);
+        let mut out = "Saved games:\n".to_string();
+        for name in names {
+            let note = self
+                .io
+                .read_slot(&name)
+                .ok()
+                .and_then(|x| quetzal::annotation(&x))
+                .unwrap_or_default();
+            out += &format!("  {:width$}  {}\n", name, note, width = width);
+        }
+        out
+    }
+
+    // Starts or stops the transcript, and sets the transcript bit of flags 2 to match so the game
+    // knows.
+    fn transcript(&mut self, path: Option<&str>) -> String {
+        let flags = self.memory.read_u16(0x10);
+        match path {
+            Some(path) => match self.io.start_transcript(path) {
+                Ok(()) => {
+                    self.memory.write_u16(0x10, flags | 1);
+                    format!("Transcript on, writing to {}.", path)
+                }
+                Err(e) => format!("Couldn't start a transcript in {}: {}.", path, e),
+            },
+            None => {
+                self.io.stop_transcript();
+                self.memory.write_u16(0x10, flags & !1);
+                "Transcript off.".to_string()
+            }
+        }
+    }
+
+    // The bytes a place in memory names: a global as Gnn, or a byte by its address in hex.
+    fn place(&self, name: &str) -> Option<(usize, usize)> {
+        match name.strip_prefix(['G', 'g']) {
+            Some(x) => match u8::from_str_radix(x, 16) {
+                Ok(n) if n < 240 => Some((self.header.globals + n as usize * 2, 2)),
+                _ => None,
+            },
+            None => usize::from_str_radix(name.trim_start_matches("0x"), 16)
+                .ok()
+                .map(|x| (x, 1)),
+        }
+    }
+
+    // Prints where a copy of the game run back through its history is, followed by a prompt.
+    fn show_past(&mut self, past: &Machine, heading: &str) {
+        let instruction = Instruction::new(&past.memory, past.ip);
+        let out = format!("{}\n{}\n{}\n\n>", heading, instruction, past.backtrace());
+        self.io.print(&out);
+    }
+
+    fn look_back(&mut self, instructions: usize) {
+        if self.history_len() == 0 {
+            return self.no_history();
+        }
+        let mut past = self.clone();
+        let mut back = 0;
+        while back < instructions && past.step_back() {
+            back += 1;
+        }
+        let heading = format!("{} back, about to run:", instructions_back(back));
+        self.show_past(&past, &heading);
+    }
+
+    fn last_write(&mut self, name: &str, addr: usize, length: usize) {
+        if self.history_len() == 0 {
+            return self.no_history();
+        }
+        let mut past = self.clone();
+        match past.run_back_to_write(addr, length) {
+            Some(back) => {
+                let heading = format!(
+                    "{} was last written {} back, by:",
+                    name,
+                    instructions_back(back)
+                );
+                self.show_past(&past, &heading);
+            }
+            None => self.io.print(&format!(
+                "[Nothing in the last {} instructions wrote {}.]\n\n>",
+                self.history_len(),
+                name
+            )),
+        }
+    }
+
+    fn no_history(&mut self) {
+        self.io.print(
+            "[There's no history to look back through. Start rustzork with --history to record \
+             it.]\n\n>",
+        );
+    }
+
+    // What the status line would show: the room from the first global and the score and moves,
+    // or the time in games that keep it, from the next two.
+    pub(crate) fn status(&self) -> String {
+        let global = |n: usize| self.memory.read_u16(self.header.globals + n * 2);
+        let room = match global(0) {
+            0 => "Nowhere".to_string(),
+            x => Object::new(&self.memory, x as usize)
+                .name(&self.memory)
+                .to_string(),
+        };
+        if self.memory.read_u8(0x1) & 0x2 != 0 {
+            format!("{}. Time: {}:{:02}.", room, global(1), global(2))
+        } else {
+            format!(
+                "{}. Score: {}. Moves: {}.",
+                room,
+                global(1) as i16,
+                global(2)
+            )
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use memory::Memory;
+use zstring::ZString;
+
+pub struct Property {
+    pub(crate) offset: usize,
+    pub(crate) index: usize,
+    pub(crate) length: usize,
+}
+
+impl Property {
+    pub fn new(memory: &Memory, offset: usize) -> Property {
+        let size = memory.read_u8(offset);
+        Property {
+            offset,
+            index: (size & 31) as usize,
+            length: (((size & 0xe0) >> 5) + 1) as usize,
+        }
+    }
+
+    // Longer properties can't be read or written as a value; like other interpreters, use
+    // their first word.
+    pub fn read(&self, memory: &Memory) -> u16 {
+        if self.length == 1 {
+            memory.read_u8(self.offset.wrapping_add(1)) as u16
+        } else {
+            memory.read_u16(self.offset.wrapping_add(1))
+        }
+    }
+
+    pub fn write(&self, memory: &mut Memory, value: u16) {
+        if self.length == 1 {
+            memory.store_u8(self.offset.wrapping_add(1), (value & 0xff) as u8);
+        } else {
+            memory.store_u16(self.offset.wrapping_add(1), value);
+        }
+    }
+}
+
+// A handle to an entry in the object table. Fields are read and written in place, so looking an
+// object up doesn't copy or decode anything.
+#[derive(Debug, Copy, Clone)]
+pub struct Object {
+    pub(crate) index: usize,
+    pub(crate) addr: usize,
+}
+
+pub const OBJECT_SIZE: usize = 9;
+pub const NUM_DEFAULTS: usize = 31;
+pub const DEFAULT_TABLE_SIZE: usize = NUM_DEFAULTS * 2;
+
+// The number of objects in the table. The header doesn't say, so like other tools this takes the
+// entries to stop where the first property table starts.
+pub(crate) fn object_count(memory: &Memory) -> usize {
+    let mut end = memory.len();
+    let mut count = 0;
+    while count < 255 {
+        let object = Object::new(memory, count + 1);
+        if object.addr + OBJECT_SIZE > end {
+            break;
+        }
+        end = end.min(object.prop_table(memory));
+        count += 1;
+    }
+    count
+}
+
+impl Object {
+    pub fn new(memory: &Memory, index: usize) -> Object {
+        Object {
+            index,
+            addr: memory.read_u16(0xa) as usize + DEFAULT_TABLE_SIZE + (index - 1) * OBJECT_SIZE,
+        }
+    }
+
+    pub fn test_attr(&self, memory: &Memory, attr: usize) -> bool {
+        (memory.read_u8(self.addr + attr / 8) & (0x80 >> (attr % 8))) != 0
+    }
+
+    pub fn set_attr(&self, memory: &mut Memory, attr: usize, value: bool) {
+        let offset = self.addr + attr / 8;
+        let bit = 0x80 >> (attr % 8);
+        let byte = memory.read_u8(offset);
+        memory.store_u8(offset, if value { byte | bit } else { byte & !bit });
+    }
+
+    pub fn parent(&self, memory: &Memory) -> usize {
+        memory.read_u8(self.addr + 4) as usize
+    }
+
+    pub fn sibling(&self, memory: &Memory) -> usize {
+        memory.read_u8(self.addr + 5) as usize
+    }
+
+    pub fn child(&self, memory: &Memory) -> usize {
+        memory.read_u8(self.addr + 6) as usize
+    }
+
+    pub fn set_parent(&self, memory: &mut Memory, value: usize) {
+        memory.store_u8(self.addr + 4, value as u8);
+    }
+
+    pub fn set_sibling(&self, memory: &mut Memory, value: usize) {
+        memory.store_u8(self.addr + 5, value as u8);
+    }
+
+    pub fn set_child(&self, memory: &mut Memory, value: usize) {
+        memory.store_u8(self.addr + 6, value as u8);
+    }
+
+    pub(crate) fn prop_table(&self, memory: &Memory) -> usize {
+        memory.read_u16(self.addr + 7) as usize
+    }
+
+    pub fn name(&self, memory: &Memory) -> ZString {
+        ZString::new(memory, self.prop_table(memory) + 1)
+    }
+
+    // The property list starts after the short name, whose length in words is the first byte of
+    // the property table.
+    fn first_property(&self, memory: &Memory) -> usize {
+        let table = self.prop_table(memory);
+        table + 1 + memory.read_u8(table) as usize * 2
+    }
+
+    // The object's properties in the order they're stored, and the address just past the zero
+    // byte that ends them.
+    pub(crate) fn properties(&self, memory: &Memory) -> (Vec<Property>, usize) {
+        let mut properties = Vec::new();
+        let mut addr = self.first_property(memory);
+        loop {
+            let p = Property::new(memory, addr);
+            if p.index == 0 {
+                return (properties, addr + 1);
+            }
+            addr = addr + p.length + 1;
+            properties.push(p);
+        }
+    }
+
+    pub fn get_property(&self, memory: &Memory, index: usize) -> Property {
+        match self.get_property_opt(memory, index) {
+            Some(p) => p,
+            None => {
+                // Defaults are plain words, without a size byte in front.
+                let default_addr = memory.read_u16(0xa) as usize + (index - 1) * 2;
+                Property {
+                    offset: default_addr.wrapping_sub(1),
+                    index,
+                    length: 2,
+                }
+            }
+        }
+    }
+
+    pub fn get_property_opt(&self, memory: &Memory, index: usize) -> Option<Property> {
+        let mut addr = self.first_property(memory);
+        loop {
+            let p = Property::new(memory, addr);
+            match p {
+                Property { index: 0, .. } => return None,
+                Property { index: i, .. } if i == index => return Some(p),
+                Property { length: l, .. } => addr = addr + l + 1,
+            }
+        }
+    }
+
+    pub fn get_next_property(&self, memory: &Memory, index: usize) -> Option<usize> {
+        let mut addr = self.first_property(memory);
+        let mut found = index == 0;
+        loop {
+            let p = Property::new(memory, addr);
+            if found || p.index == 0 {
+                // Asking for the property after the last one gives 0; asking for one that isn't
+                // there is an error.
+                return if found { Some(p.index) } else { None };
+            }
+            found = p.index == index;
+            addr = addr + p.length + 1;
+        }
+    }
+
+    pub fn remove(&self, memory: &mut Memory) {
+        let parent = self.parent(memory);
+        if parent != 0 {
+            let parent = Object::new(memory, parent);
+            let sibling = self.sibling(memory);
+            let first = parent.child(memory);
+
+            if first == self.index {
+                parent.set_child(memory, sibling);
+            } else if first != 0 {
+                let mut child = Object::new(memory, first);
+                // A broken tree may not hold the object at all, or loop; there are at most 255
+                // objects to walk past.
+                for _ in 0..255 {
+                    match child.sibling(memory) {
+                        0 => break,
+                        x if x == self.index => {
+                            child.set_sibling(memory, sibling);
+                            break;
+                        }
+                        x => child = Object::new(memory, x),
+                    }
+                }
+            }
+        }
+        self.set_parent(memory, 0);
+        self.set_sibling(memory, 0);
+    }
+
+    pub fn insert(&self, memory: &mut Memory, dest: &Object) {
+        self.remove(memory);
+        self.set_sibling(memory, dest.child(memory));
+        self.set_parent(memory, dest.index);
+        dest.set_child(memory, self.index);
+    }
+}
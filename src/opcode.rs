@@ -0,0 +1,335 @@
+use instruction::Encoding;
+
+// Every opcode name in the spec's tables, decoded once from an instruction's form and number.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Rtrue,
+    Rfalse,
+    Print,
+    PrintRet,
+    Nop,
+    Save,
+    Restore,
+    Restart,
+    RetPopped,
+    Pop,
+    Quit,
+    NewLine,
+    ShowStatus,
+    Verify,
+    Extended,
+    Piracy,
+    Jz,
+    GetSibling,
+    GetChild,
+    GetParent,
+    GetPropLen,
+    Inc,
+    Dec,
+    PrintAddr,
+    Call1s,
+    RemoveObj,
+    PrintObj,
+    Ret,
+    Jump,
+    PrintPaddr,
+    Load,
+    Not,
+    None,
+    Je,
+    Jl,
+    Jg,
+    DecChk,
+    IncChk,
+    Jin,
+    Test,
+    Or,
+    And,
+    TestAttr,
+    SetAttr,
+    ClearAttr,
+    Store,
+    InsertObj,
+    Loadw,
+    Loadb,
+    GetProp,
+    GetPropAddr,
+    GetNextProp,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Call2s,
+    Call2n,
+    SetColour,
+    Throw,
+    Call,
+    Storew,
+    Storeb,
+    PutProp,
+    Sread,
+    PrintChar,
+    PrintNum,
+    Random,
+    Push,
+    Pull,
+    SplitWindow,
+    SetWindow,
+    CallVs2,
+    EraseWindow,
+    EraseLine,
+    SetCursor,
+    GetCursor,
+    SetTextStyle,
+    BufferMode,
+    OutputStream,
+    InputStream,
+    SoundEffect,
+    ReadChar,
+    ScanTable,
+    NotV4,
+    CallVn,
+    CallVn2,
+    Tokenise,
+    EncodeText,
+    CopyTable,
+    PrintTable,
+    CheckArgCount,
+    Unknown,
+}
+
+const OPS_0OP: [Opcode; 16] = [
+    Opcode::Rtrue,
+    Opcode::Rfalse,
+    Opcode::Print,
+    Opcode::PrintRet,
+    Opcode::Nop,
+    Opcode::Save,
+    Opcode::Restore,
+    Opcode::Restart,
+    Opcode::RetPopped,
+    Opcode::Pop,
+    Opcode::Quit,
+    Opcode::NewLine,
+    Opcode::ShowStatus,
+    Opcode::Verify,
+    Opcode::Extended,
+    Opcode::Piracy,
+];
+
+const OPS_1OP: [Opcode; 16] = [
+    Opcode::Jz,
+    Opcode::GetSibling,
+    Opcode::GetChild,
+    Opcode::GetParent,
+    Opcode::GetPropLen,
+    Opcode::Inc,
+    Opcode::Dec,
+    Opcode::PrintAddr,
+    Opcode::Call1s,
+    Opcode::RemoveObj,
+    Opcode::PrintObj,
+    Opcode::Ret,
+    Opcode::Jump,
+    Opcode::PrintPaddr,
+    Opcode::Load,
+    Opcode::Not,
+];
+
+const OPS_2OP: [Opcode; 32] = [
+    Opcode::None,
+    Opcode::Je,
+    Opcode::Jl,
+    Opcode::Jg,
+    Opcode::DecChk,
+    Opcode::IncChk,
+    Opcode::Jin,
+    Opcode::Test,
+    Opcode::Or,
+    Opcode::And,
+    Opcode::TestAttr,
+    Opcode::SetAttr,
+    Opcode::ClearAttr,
+    Opcode::Store,
+    Opcode::InsertObj,
+    Opcode::Loadw,
+    Opcode::Loadb,
+    Opcode::GetProp,
+    Opcode::GetPropAddr,
+    Opcode::GetNextProp,
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Mul,
+    Opcode::Div,
+    Opcode::Mod,
+    Opcode::Call2s,
+    Opcode::Call2n,
+    Opcode::SetColour,
+    Opcode::Throw,
+    Opcode::Unknown,
+    Opcode::Unknown,
+    Opcode::Unknown,
+];
+
+const OPS_VAR: [Opcode; 32] = [
+    Opcode::Call,
+    Opcode::Storew,
+    Opcode::Storeb,
+    Opcode::PutProp,
+    Opcode::Sread,
+    Opcode::PrintChar,
+    Opcode::PrintNum,
+    Opcode::Random,
+    Opcode::Push,
+    Opcode::Pull,
+    Opcode::SplitWindow,
+    Opcode::SetWindow,
+    Opcode::CallVs2,
+    Opcode::EraseWindow,
+    Opcode::EraseLine,
+    Opcode::SetCursor,
+    Opcode::GetCursor,
+    Opcode::SetTextStyle,
+    Opcode::BufferMode,
+    Opcode::OutputStream,
+    Opcode::InputStream,
+    Opcode::SoundEffect,
+    Opcode::ReadChar,
+    Opcode::ScanTable,
+    Opcode::NotV4,
+    Opcode::CallVn,
+    Opcode::CallVn2,
+    Opcode::Tokenise,
+    Opcode::EncodeText,
+    Opcode::CopyTable,
+    Opcode::PrintTable,
+    Opcode::CheckArgCount,
+];
+
+impl Opcode {
+    pub fn new(optype: Encoding, number: usize) -> Opcode {
+        let table: &[Opcode] = match optype {
+            Encoding::Op0 => &OPS_0OP,
+            Encoding::Op1 => &OPS_1OP,
+            Encoding::Op2 => &OPS_2OP,
+            Encoding::Var => &OPS_VAR,
+        };
+        *table.get(number).unwrap_or(&Opcode::Unknown)
+    }
+
+    // The form and number of the opcode with the given name, the reverse of new and name.
+    pub fn find(name: &str) -> Option<(Encoding, usize)> {
+        let tables: [(Encoding, &[Opcode]); 4] = [
+            (Encoding::Op0, &OPS_0OP),
+            (Encoding::Op1, &OPS_1OP),
+            (Encoding::Op2, &OPS_2OP),
+            (Encoding::Var, &OPS_VAR),
+        ];
+        tables.iter().find_map(|&(optype, table)| {
+            table
+                .iter()
+                .position(|&x| x != Opcode::Unknown && x.name() == name)
+                .map(|number| (optype, number))
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Rtrue => "rtrue",
+            Opcode::Rfalse => "rfalse",
+            Opcode::Print => "print",
+            Opcode::PrintRet => "print_ret",
+            Opcode::Nop => "no",
+            Opcode::Save => "save",
+            Opcode::Restore => "restore",
+            Opcode::Restart => "restart",
+            Opcode::RetPopped => "ret_popped",
+            Opcode::Pop => "pop",
+            Opcode::Quit => "quit",
+            Opcode::NewLine => "new_line",
+            Opcode::ShowStatus => "show_status",
+            Opcode::Verify => "verify",
+            Opcode::Extended => "extended",
+            Opcode::Piracy => "piracy",
+            Opcode::Jz => "jz",
+            Opcode::GetSibling => "get_sibling",
+            Opcode::GetChild => "get_child",
+            Opcode::GetParent => "get_parent",
+            Opcode::GetPropLen => "get_prop_len",
+            Opcode::Inc => "inc",
+            Opcode::Dec => "dec",
+            Opcode::PrintAddr => "print_addr",
+            Opcode::Call1s => "call_1s",
+            Opcode::RemoveObj => "remove_obj",
+            Opcode::PrintObj => "print_obj",
+            Opcode::Ret => "ret",
+            Opcode::Jump => "jump",
+            Opcode::PrintPaddr => "print_paddr",
+            Opcode::Load => "load",
+            Opcode::Not => "not",
+            Opcode::None => "none",
+            Opcode::Je => "je",
+            Opcode::Jl => "jl",
+            Opcode::Jg => "jg",
+            Opcode::DecChk => "dec_chk",
+            Opcode::IncChk => "inc_chk",
+            Opcode::Jin => "jin",
+            Opcode::Test => "test",
+            Opcode::Or => "or",
+            Opcode::And => "and",
+            Opcode::TestAttr => "test_attr",
+            Opcode::SetAttr => "set_attr",
+            Opcode::ClearAttr => "clear_attr",
+            Opcode::Store => "store",
+            Opcode::InsertObj => "insert_obj",
+            Opcode::Loadw => "loadw",
+            Opcode::Loadb => "loadb",
+            Opcode::GetProp => "get_prop",
+            Opcode::GetPropAddr => "get_prop_addr",
+            Opcode::GetNextProp => "get_next_prop",
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Mul => "mul",
+            Opcode::Div => "div",
+            Opcode::Mod => "mod",
+            Opcode::Call2s => "call_2s",
+            Opcode::Call2n => "call_2n",
+            Opcode::SetColour => "set_colour",
+            Opcode::Throw => "throw",
+            Opcode::Call => "call",
+            Opcode::Storew => "storew",
+            Opcode::Storeb => "storeb",
+            Opcode::PutProp => "put_prop",
+            Opcode::Sread => "sread",
+            Opcode::PrintChar => "print_char",
+            Opcode::PrintNum => "print_num",
+            Opcode::Random => "random",
+            Opcode::Push => "push",
+            Opcode::Pull => "pull",
+            Opcode::SplitWindow => "split_window",
+            Opcode::SetWindow => "set_window",
+            Opcode::CallVs2 => "call_vs2",
+            Opcode::EraseWindow => "erase_window",
+            Opcode::EraseLine => "erase_line",
+            Opcode::SetCursor => "set_cursor",
+            Opcode::GetCursor => "get_cursor",
+            Opcode::SetTextStyle => "set_text_style",
+            Opcode::BufferMode => "buffer_mode",
+            Opcode::OutputStream => "output_stream",
+            Opcode::InputStream => "input_stream",
+            Opcode::SoundEffect => "sound_effect",
+            Opcode::ReadChar => "read_char",
+            Opcode::ScanTable => "scan_table",
+            Opcode::NotV4 => "not_v4",
+            Opcode::CallVn => "call_vn",
+            Opcode::CallVn2 => "call_vn2",
+            Opcode::Tokenise => "tokenise",
+            Opcode::EncodeText => "encode_text",
+            Opcode::CopyTable => "copy_table",
+            Opcode::PrintTable => "print_table",
+            Opcode::CheckArgCount => "check_arg_count",
+            Opcode::Unknown => "unknown",
+        }
+    }
+}
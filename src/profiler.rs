@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Copy, Clone)]
+pub enum ProfileFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RoutineProfile {
+    pub(crate) calls: u64,
+    pub(crate) instructions: u64,
+}
+
+// Counts executed instructions per opcode, per routine and per sread turn. Routine 0 stands for
+// the main routine, which has no frame.
+pub struct Profiler {
+    pub(crate) format: ProfileFormat,
+    pub(crate) output: Option<String>,
+    pub(crate) instructions: u64,
+    pub(crate) opcodes: HashMap<&'static str, u64>,
+    pub(crate) routines: HashMap<usize, RoutineProfile>,
+    pub(crate) turns: Vec<u64>,
+    pub(crate) turn: u64,
+}
+
+impl Profiler {
+    pub fn new(format: ProfileFormat, output: Option<String>) -> Profiler {
+        Profiler {
+            format,
+            output,
+            instructions: 0,
+            opcodes: HashMap::new(),
+            routines: HashMap::new(),
+            turns: Vec::new(),
+            turn: 0,
+        }
+    }
+
+    pub fn record(&mut self, name: &'static str, routine: usize) {
+        self.instructions += 1;
+        self.turn += 1;
+        *self.opcodes.entry(name).or_insert(0) += 1;
+        self.routines.entry(routine).or_default().instructions += 1;
+    }
+
+    pub fn record_call(&mut self, routine: usize) {
+        self.routines.entry(routine).or_default().calls += 1;
+    }
+
+    pub fn end_turn(&mut self) {
+        self.turns.push(self.turn);
+        self.turn = 0;
+    }
+
+    pub fn sorted_opcodes(&self) -> Vec<(&'static str, u64)> {
+        let mut opcodes: Vec<_> = self.opcodes.iter().map(|(&k, &v)| (k, v)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        opcodes
+    }
+
+    pub fn sorted_routines(&self) -> Vec<(usize, RoutineProfile)> {
+        let mut routines: Vec<_> = self.routines.iter().map(|(&k, &v)| (k, v)).collect();
+        routines.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(&b.0)));
+        routines
+    }
+
+    // Completed turns, plus whatever ran after the last sread.
+    pub fn all_turns(&self) -> Vec<u64> {
+        let mut turns = self.turns.clone();
+        if self.turn > 0 {
+            turns.push(self.turn);
+        }
+        turns
+    }
+
+    pub fn report(&self) -> String {
+        match self.format {
+            ProfileFormat::Text => self.report_text(),
+            ProfileFormat::Csv => self.report_csv(),
+            ProfileFormat::Json => self.report_json(),
+        }
+    }
+
+    pub fn report_text(&self) -> String {
+        let turns = self.all_turns();
+        let mut out = format!("{} instructions", self.instructions);
+        if !turns.is_empty() {
+            out += &format!(
+                " over {} turns ({} per turn, min {}, max {})",
+                turns.len(),
+                self.instructions / turns.len() as u64,
+                turns.iter().min().unwrap(),
+                turns.iter().max().unwrap()
+            );
+        }
+        out += "\n\n   count      %  opcode\n";
+        for (name, count) in self.sorted_opcodes() {
+            let percent = 100.0 * count as f64 / self.instructions as f64;
+            out += &format!("{:8} {:5.1}%  {}\n", count, percent, name);
+        }
+        out += "\n   count      %    calls  routine\n";
+        for (addr, routine) in self.sorted_routines() {
+            let percent = 100.0 * routine.instructions as f64 / self.instructions as f64;
+            let name = if addr == 0 {
+                "main".to_string()
+            } else {
+                format!("{:08X}", addr)
+            };
+            out += &format!(
+                "{:8} {:5.1}% {:8}  {}\n",
+                routine.instructions, percent, routine.calls, name
+            );
+        }
+        out
+    }
+
+    pub fn report_csv(&self) -> String {
+        let mut out = String::from("kind,key,count,calls\n");
+        for (name, count) in self.sorted_opcodes() {
+            out += &format!("opcode,{},{},\n", name, count);
+        }
+        for (addr, routine) in self.sorted_routines() {
+            out += &format!(
+                "routine,{:08X},{},{}\n",
+                addr, routine.instructions, routine.calls
+            );
+        }
+        for (turn, count) in self.all_turns().iter().enumerate() {
+            out += &format!("turn,{},{},\n", turn, count);
+        }
+        out
+    }
+
+    pub fn report_json(&self) -> String {
+        let opcodes: Vec<String> = self
+            .sorted_opcodes()
+            .iter()
+            .map(|&(name, count)| format!("\"{}\":{}", name, count))
+            .collect();
+        let routines: Vec<String> = self
+            .sorted_routines()
+            .iter()
+            .map(|&(addr, routine)| {
+                format!(
+                    "{{\"addr\":{},\"instructions\":{},\"calls\":{}}}",
+                    addr, routine.instructions, routine.calls
+                )
+            })
+            .collect();
+        let turns: Vec<String> = self.all_turns().iter().map(|t| t.to_string()).collect();
+        format!(
+            "{{\"instructions\":{},\"opcodes\":{{{}}},\"routines\":[{}],\"turns\":[{}]}}\n",
+            self.instructions,
+            opcodes.join(","),
+            routines.join(","),
+            turns.join(",")
+        )
+    }
+}
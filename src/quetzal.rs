@@ -0,0 +1,270 @@
+// Save files in the Quetzal format (http://inform-fiction.org/zmachine/standards/quetzal) shared by
+// most Z-machine interpreters: an IFF FORM of type IFZS holding the story's identity in IFhd,
+// dynamic memory in CMem (compressed against the story) or UMem, and the call stack in Stks. An
+// ANNO chunk describes the save for people browsing them.
+//
+// rustzork saves at a read instruction rather than after a save instruction, so the PC in IFhd is
+// the address of the read, which runs again once the game is restored. The standard has it point
+// into a save instruction instead, so rustzork's saves can't be restored by other interpreters, and
+// saves from them are refused rather than restored to the wrong place.
+
+use instruction::{Instruction, Operand, Return, MAX_INSTRUCTION_LENGTH};
+use memory::{Frame, Memory};
+use opcode::Opcode;
+use rand::rngs::SmallRng;
+use snapshot::{compress, decompress, Snapshot};
+
+// The release number, serial number and checksum from the header, as IFhd stores them.
+pub(crate) fn identity(memory: &Memory) -> [u8; 10] {
+    let mut id = [0; 10];
+    for (x, &addr) in id
+        .iter_mut()
+        .zip(&[0x2, 0x3, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x1c, 0x1d])
+    {
+        *x = memory.read_u8(addr);
+    }
+    id
+}
+
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, x: u16) {
+    out.extend_from_slice(&x.to_be_bytes());
+}
+
+fn push_u24(out: &mut Vec<u8>, x: usize) {
+    out.extend_from_slice(&(x as u32).to_be_bytes()[1..]);
+}
+
+pub(crate) fn write(id: &[u8; 10], snapshot: &Snapshot, annotation: &str) -> Vec<u8> {
+    let mut header = id.to_vec();
+    push_u24(&mut header, snapshot.ip);
+
+    // A dummy frame holds the main routine's evaluation stack.
+    let mut stacks = vec![0; 6];
+    let bottom = snapshot
+        .frames
+        .first()
+        .map_or(snapshot.stack.len(), |f| f.stack_start);
+    push_u16(&mut stacks, bottom as u16);
+    for &x in &snapshot.stack[..bottom] {
+        push_u16(&mut stacks, x);
+    }
+    for (n, frame) in snapshot.frames.iter().enumerate() {
+        let end = snapshot
+            .frames
+            .get(n + 1)
+            .map_or(snapshot.stack.len(), |f| f.stack_start);
+        let (flags, var) = match frame.return_storage {
+            Return::Variable(x) | Return::Indirect(x) => (0, x),
+            Return::Omitted => (0x10, 0),
+        };
+        push_u24(&mut stacks, frame.return_addr);
+        stacks.push(frame.num_locals as u8 | flags);
+        stacks.push(var);
+        // Version 3 can't ask how many arguments a routine was given, so they aren't kept.
+        stacks.push(0);
+        push_u16(
+            &mut stacks,
+            (end - frame.stack_start - frame.num_locals) as u16,
+        );
+        for &x in &snapshot.stack[frame.stack_start..end] {
+            push_u16(&mut stacks, x);
+        }
+    }
+
+    form(&[
+        (*b"IFhd", &header),
+        (*b"CMem", &snapshot.memory),
+        (*b"Stks", &stacks),
+        (*b"ANNO", annotation.as_bytes()),
+    ])
+}
+
+fn form(chunks: &[Chunk<'_>]) -> Vec<u8> {
+    let mut body = b"IFZS".to_vec();
+    for (id, data) in chunks {
+        chunk(&mut body, id, data);
+    }
+    let mut out = b"FORM".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+// A chunk's ID and contents.
+pub(crate) type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+// The chunks of a Quetzal file in the order they appear.
+pub(crate) fn chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    if data.len() < 12 || &data[..4] != b"FORM" || &data[8..12] != b"IFZS" {
+        return Err("not a Quetzal save file".to_string());
+    }
+    let end =
+        (u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize + 8).min(data.len());
+    let mut chunks = Vec::new();
+    let mut at = 12;
+    while at + 8 <= end {
+        let id = [data[at], data[at + 1], data[at + 2], data[at + 3]];
+        let len = u32::from_be_bytes([data[at + 4], data[at + 5], data[at + 6], data[at + 7]]);
+        let start = at + 8;
+        let stop = start
+            .checked_add(len as usize)
+            .filter(|&x| x <= end)
+            .ok_or_else(|| {
+                format!(
+                    "{} chunk runs past the end of the file",
+                    String::from_utf8_lossy(&id)
+                )
+            })?;
+        chunks.push((id, &data[start..stop]));
+        at = stop + (len as usize & 1);
+    }
+    Ok(chunks)
+}
+
+fn find<'a>(chunks: &[Chunk<'a>], id: &[u8; 4]) -> Option<&'a [u8]> {
+    chunks.iter().find(|x| &x.0 == id).map(|x| x.1)
+}
+
+pub(crate) fn annotation(data: &[u8]) -> Option<String> {
+    let chunks = chunks(data).ok()?;
+    find(&chunks, b"ANNO").map(|x| String::from_utf8_lossy(x).to_string())
+}
+
+// The release, serial number and checksum of an IFhd chunk or the identity of a story.
+pub(crate) fn describe(id: &[u8]) -> String {
+    let serial: String = id[2..8].iter().map(|&x| x as char).collect();
+    format!(
+        "release {}, serial {}, checksum {:02x}{:02x}",
+        (id[0] as u16) << 8 | id[1] as u16,
+        serial,
+        id[8],
+        id[9]
+    )
+}
+
+// Dynamic memory as saved, from whichever of CMem and UMem the save has. CMem wins if there are
+// both.
+pub(crate) fn dynamic_memory(chunks: &[Chunk<'_>], initial: &[u8]) -> Result<Vec<u8>, String> {
+    match (find(chunks, b"CMem"), find(chunks, b"UMem")) {
+        (Some(x), _) => decompress(initial, x)
+            .ok_or_else(|| "the CMem chunk is longer than dynamic memory".to_string()),
+        (None, Some(x)) if x.len() == initial.len() => Ok(x.to_vec()),
+        (None, Some(_)) => Err("the UMem chunk isn't the size of dynamic memory".to_string()),
+        (None, None) => Err("the save has no memory chunk".to_string()),
+    }
+}
+
+// The save with its memory in a CMem chunk if compressed is set, or a UMem chunk if not. The other
+// chunks are copied as they are.
+pub(crate) fn convert(data: &[u8], initial: &[u8], compressed: bool) -> Result<Vec<u8>, String> {
+    let chunks = chunks(data)?;
+    let dynamic = dynamic_memory(&chunks, initial)?;
+    let memory = match compressed {
+        true => compress(initial, &dynamic),
+        false => dynamic,
+    };
+    let id = if compressed { *b"CMem" } else { *b"UMem" };
+    let mut out: Vec<Chunk<'_>> = Vec::new();
+    for &(x, data) in &chunks {
+        match &x {
+            b"CMem" | b"UMem" if out.iter().any(|y| y.0 == id) => {}
+            b"CMem" | b"UMem" => out.push((id, &memory)),
+            _ => out.push((x, data)),
+        }
+    }
+    Ok(form(&out))
+}
+
+// The routine a frame is running, from the call instruction that ends where the frame returns to.
+// Calls through a variable can't be followed, and give 0.
+fn caller_routine(memory: &Memory, return_addr: usize) -> usize {
+    for length in 2..=MAX_INSTRUCTION_LENGTH.min(return_addr) {
+        let i = Instruction::new(memory, return_addr - length);
+        if i.length != length || i.op != Opcode::Call {
+            continue;
+        }
+        match i.arg(0) {
+            Operand::Large(x) => return x as usize * 2,
+            Operand::Small(x) => return x as usize * 2,
+            _ => {}
+        }
+    }
+    0
+}
+
+// Reads a Quetzal file of the story identified by id. Memory is the story's, initial its dynamic
+// memory as loaded, and rng and instructions are carried over from the running game, since Quetzal
+// doesn't keep them.
+pub(crate) fn read(
+    data: &[u8],
+    id: &[u8; 10],
+    memory: &Memory,
+    initial: &[u8],
+    rng: SmallRng,
+    instructions: u64,
+) -> Result<Snapshot, String> {
+    let chunks = chunks(data)?;
+    let header = find(&chunks, b"IFhd").ok_or("the save has no IFhd chunk")?;
+    if header.len() < 13 {
+        return Err("the IFhd chunk is too short".to_string());
+    }
+    if header[..10] != id[..] {
+        return Err("the save is of a different story".to_string());
+    }
+    let ip = (header[10] as usize) << 16 | (header[11] as usize) << 8 | header[12] as usize;
+    if Instruction::new(memory, ip).op != Opcode::Sread {
+        return Err(
+            "the save wasn't made by rustzork, which saves at a read instruction".to_string(),
+        );
+    }
+    let dynamic = dynamic_memory(&chunks, initial)?;
+
+    let stacks = find(&chunks, b"Stks").ok_or("the save has no Stks chunk")?;
+    let mut stack = Vec::new();
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut at = 0;
+    while at < stacks.len() {
+        let short = || "the Stks chunk is cut short".to_string();
+        let frame = stacks.get(at..at + 8).ok_or_else(short)?;
+        let return_addr = (frame[0] as usize) << 16 | (frame[1] as usize) << 8 | frame[2] as usize;
+        let num_locals = (frame[3] & 0xf) as usize;
+        let words = num_locals + ((frame[6] as usize) << 8 | frame[7] as usize);
+        let values = stacks.get(at + 8..at + 8 + words * 2).ok_or_else(short)?;
+        if at == 0 {
+            if num_locals != 0 {
+                return Err("the main routine has locals".to_string());
+            }
+        } else {
+            frames.push(Frame {
+                addr: caller_routine(memory, return_addr),
+                stack_start: stack.len(),
+                num_locals,
+                return_storage: match frame[3] & 0x10 {
+                    0 => Return::Variable(frame[4]),
+                    _ => Return::Omitted,
+                },
+                return_addr,
+            });
+        }
+        stack.extend(values.chunks(2).map(|x| (x[0] as u16) << 8 | x[1] as u16));
+        at += 8 + words * 2;
+    }
+
+    Ok(Snapshot {
+        memory: compress(initial, &dynamic),
+        stack,
+        frames,
+        ip,
+        rng,
+        instructions,
+    })
+}
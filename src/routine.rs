@@ -0,0 +1,100 @@
+use header::Header;
+use instruction::{Instruction, MAX_INSTRUCTION_LENGTH};
+use memory::Memory;
+use std::cmp;
+use zstring::ZString;
+
+pub struct Routine {
+    pub(crate) addr: usize,
+    pub(crate) num_locals: usize,
+    pub(crate) instructions: Vec<Instruction>,
+}
+
+impl Routine {
+    // Decodes the routine at addr, or None if the bytes there don't look like code.
+    pub fn new(memory: &Memory, addr: usize) -> Option<Routine> {
+        if addr >= memory.len() {
+            return None;
+        }
+        let num_locals = memory.read_u8(addr) as usize;
+        if num_locals > 15 {
+            return None;
+        }
+        let mut offset = addr + 1 + num_locals * 2;
+        let mut furthest = offset;
+        let mut instructions = Vec::new();
+        loop {
+            if offset + MAX_INSTRUCTION_LENGTH > memory.len() {
+                return None;
+            }
+            // print and print_ret carry an inline string which may run off the end of memory.
+            let op = memory.read_u8(offset);
+            if (op == 0xb2 || op == 0xb3) && !ZString::fits(memory, offset + 1) {
+                return None;
+            }
+            let i = Instruction::new(memory, offset);
+            if !i.is_valid() {
+                return None;
+            }
+            if let Some(target) = i.branch_target() {
+                if target < addr || target >= memory.len() {
+                    return None;
+                }
+                furthest = cmp::max(furthest, target);
+            }
+            offset += i.length;
+            let terminal = i.is_terminal();
+            instructions.push(i);
+            if terminal && offset > furthest {
+                break;
+            }
+        }
+        Some(Routine {
+            addr,
+            num_locals,
+            instructions,
+        })
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn end(&self) -> usize {
+        match self.instructions.last() {
+            Some(i) => i.offset + i.length,
+            None => self.addr + 1 + self.num_locals * 2,
+        }
+    }
+
+    // Decodes consecutive routines from start until something that isn't code (or stop) is hit.
+    pub fn sweep(memory: &Memory, start: usize, stop: usize) -> Vec<Routine> {
+        let mut routines = Vec::new();
+        let mut addr = start;
+        while addr < stop {
+            match Routine::new(memory, addr) {
+                Some(r) => {
+                    addr = (r.end() + 1) & !1;
+                    routines.push(r);
+                }
+                None => break,
+            }
+        }
+        routines
+    }
+
+    // Finds all routines in high memory. Code is assumed to start somewhere between the start of
+    // high memory and the main routine, and to run contiguously until the string area.
+    pub fn find_all(memory: &Memory, header: &Header) -> Vec<Routine> {
+        let main = (memory.read_u16(0x6) as usize).saturating_sub(1);
+        let start = ((header.high_start + 1) & !1..main)
+            .step_by(2)
+            .find(|&addr| {
+                Routine::sweep(memory, addr, main)
+                    .last()
+                    .is_some_and(|r| (r.end() + 1) & !1 == main)
+            })
+            .unwrap_or(main);
+        Routine::sweep(memory, start, memory.len())
+    }
+}
@@ -0,0 +1,66 @@
+use memory::Frame;
+use rand::rngs::SmallRng;
+
+// The state of a running game, from which it can carry on as if nothing had happened since.
+// Dynamic memory is kept as its difference from the story as loaded, so a snapshot of a typical
+// game takes a few hundred bytes.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub(crate) memory: Vec<u8>,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) frames: Vec<Frame>,
+    pub(crate) ip: usize,
+    pub(crate) rng: SmallRng,
+    pub(crate) instructions: u64,
+}
+
+impl Snapshot {
+    // The number of bytes dynamic memory takes up in the snapshot.
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
+}
+
+// XORs memory with the original and run-length encodes the result: a byte that is the same as
+// the original is written as a zero followed by the number of further unchanged bytes, up to 255,
+// and any other byte as its XOR with the original. Trailing unchanged bytes are left out. This is
+// the CMem encoding of Quetzal save files.
+pub(crate) fn compress(original: &[u8], memory: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut run = 0;
+    for (a, b) in original.iter().zip(memory) {
+        let x = a ^ b;
+        if x == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 0 {
+            let n = run.min(256);
+            out.push(0);
+            out.push((n - 1) as u8);
+            run -= n;
+        }
+        out.push(x);
+    }
+    out
+}
+
+// Rebuilds memory from the original and the output of compress. Returns None if the data runs
+// past the end of the original.
+pub(crate) fn decompress(original: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let mut memory = original.to_vec();
+    let mut at = 0;
+    let mut bytes = data.iter();
+    while let Some(&x) = bytes.next() {
+        if x == 0 {
+            at += *bytes.next()? as usize + 1;
+            if at > memory.len() {
+                return None;
+            }
+        } else {
+            *memory.get_mut(at)? ^= x;
+            at += 1;
+        }
+    }
+    Some(memory)
+}
@@ -0,0 +1,1918 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Compiles a subset of ZIL, the MDL dialect Infocom wrote its games in, to the assembly that
+// assemble() turns into a V3 story. The game is compiled together with a small library
+// (src/library.zil) holding a parser and the MAIN-LOOP and PERFORM routines; a game replaces any
+// of its definitions by making one of its own with the same name. The story starts by calling
+// the game's GO routine.
+//
+// Top-level forms are ROUTINE, OBJECT, ROOM, GLOBAL, CONSTANT, SYNTAX, SYNONYM, BUZZ,
+// DIRECTIONS and VERSION (which must be ZIP or 3). Routine bodies can use:
+//
+//     COND AND OR NOT REPEAT PROG BIND RETURN AGAIN RTRUE RFALSE SET SETG INC DEC APPLY
+//     TELL PRINTI PRINTR PRINT PRINTN PRINTD PRINTB PRINTC CRLF READ QUIT RESTART PUSH
+//     + - * / MOD BAND BOR BCOM RANDOM GET GETB PUT PUTB TABLE LTABLE ITABLE
+//     GETP PUTP GETPT PTSIZE NEXTP LOC MOVE REMOVE FSET FCLEAR VALUE
+//     EQUAL? ==? =? N==? N=? L? G? L=? G=? 0? ZERO? 1? T? BTST IN? FSET? FIRST? NEXT?
+//     IGRTR? DLESS? VERB? PRSO? PRSI? VERIFY SAVE RESTORE
+//
+// and calls to other routines with up to three arguments. Flags become attributes and property
+// names get numbers as they are first used, with directions numbered down from 31. Besides the
+// atoms a game defines there are the constants P?name for properties, V?name for the actions
+// named in SYNTAX (V?WALK is the action for a bare direction), W?word for dictionary words and
+// LAST-OBJECT, the number of objects. In strings | is a line break, and a line break in the
+// source is a single space.
+
+const LIBRARY: &str = include_str!("library.zil");
+const MAX_LOCALS: usize = 15;
+const MAX_ARGS: usize = 3;
+const NUM_PROPERTIES: usize = 31;
+const NUM_FLAGS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Atom(String),
+    Number(i32),
+    Str(String),
+    // A form and the line it starts on.
+    Form(Vec<Value>, usize),
+    List(Vec<Value>),
+    Local(String),
+    Global(String),
+}
+
+struct Reader<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_space(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.next();
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+
+    // The values up to a closing bracket, which is consumed.
+    fn items(&mut self, close: char) -> Result<Vec<Value>, String> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_space();
+            match self.chars.peek() {
+                None => return Err(self.error(&format!("missing {}", close))),
+                Some(&c) if c == close => {
+                    self.next();
+                    return Ok(items);
+                }
+                _ => {
+                    if let Some(x) = self.read()? {
+                        items.push(x);
+                    }
+                }
+            }
+        }
+    }
+
+    // The next value, skipping comments in front of it.
+    fn value(&mut self) -> Result<Value, String> {
+        loop {
+            self.skip_space();
+            if let Some(x) = self.read()? {
+                return Ok(x);
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.value()? {
+            Value::Atom(x) => Ok(x),
+            _ => Err(self.error("expected a name after , or .")),
+        }
+    }
+
+    fn atom(&mut self) -> String {
+        let mut text = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || "<>()[]{}\";,".contains(c) {
+                break;
+            }
+            self.next();
+            if c == '\\' {
+                text.extend(self.next());
+            } else {
+                text.push(c);
+            }
+        }
+        text
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(text),
+                Some('\\') => text.extend(self.next()),
+                Some('|') => {
+                    text.push('\n');
+                    if self.chars.peek().is_some_and(|&c| c == '\r' || c == '\n') {
+                        self.skip_space();
+                    }
+                }
+                Some(c) if c == '\r' || c == '\n' => {
+                    while text.ends_with(' ') {
+                        text.pop();
+                    }
+                    text.push(' ');
+                    self.skip_space();
+                }
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    // The next value, or None for a comment or a declaration.
+    fn read(&mut self) -> Result<Option<Value>, String> {
+        let line = self.line;
+        let c = self
+            .next()
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        Ok(Some(match c {
+            '<' => Value::Form(self.items('>')?, line),
+            '(' => Value::List(self.items(')')?),
+            '[' => Value::List(self.items(']')?),
+            '"' => Value::Str(self.string()?),
+            ',' => Value::Global(self.name()?),
+            '.' => Value::Local(self.name()?),
+            '\'' => return self.read(),
+            ';' => {
+                self.value()?;
+                return Ok(None);
+            }
+            '!' => match (self.next(), self.next()) {
+                (Some('\\'), Some(c)) => Value::Number(c as i32),
+                _ => return Err(self.error("expected a character after !\\")),
+            },
+            '#' => {
+                let name = self.atom();
+                if name == "DECL" {
+                    self.value()?;
+                    return Ok(None);
+                }
+                let radix: u32 = name
+                    .parse()
+                    .map_err(|_| self.error(&format!("#{} isn't supported", name)))?;
+                self.skip_space();
+                let digits = self.atom();
+                let x = i32::from_str_radix(&digits, radix).map_err(|_| {
+                    self.error(&format!("{:?} isn't a base {} number", digits, radix))
+                })?;
+                Value::Number(x)
+            }
+            '%' => return Err(self.error("compile-time evaluation isn't supported")),
+            c if "<>()[]{}\";,".contains(c) => return Err(self.error(&format!("unexpected {}", c))),
+            c => {
+                let mut text = c.to_string();
+                text += &self.atom();
+                let octal = text
+                    .strip_prefix('*')
+                    .and_then(|x| x.strip_suffix('*'))
+                    .and_then(|x| i32::from_str_radix(x, 8).ok());
+                match (text.parse::<i32>(), octal) {
+                    (Ok(x), _) | (_, Some(x)) => Value::Number(x),
+                    _ => Value::Atom(text),
+                }
+            }
+        }))
+    }
+}
+
+fn read(source: &str) -> Result<Vec<Value>, String> {
+    let mut reader = Reader {
+        chars: source.chars().peekable(),
+        line: 1,
+    };
+    let mut values = Vec::new();
+    loop {
+        reader.skip_space();
+        if reader.chars.peek().is_none() {
+            return Ok(values);
+        }
+        if let Some(x) = reader.read()? {
+            values.push(x);
+        }
+    }
+}
+
+// The assembly name of a ZIL atom. Assembly names are lower case, so they never clash with
+// registers like L00 and SP.
+fn mangle(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c.to_ascii_lowercase());
+        } else if c == '-' {
+            out.push('_');
+        } else {
+            out += &format!("_{:02x}", c as u32);
+        }
+    }
+    out
+}
+
+fn quote(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn atom(value: Option<&Value>) -> Result<&str, String> {
+    match value {
+        Some(Value::Atom(x)) => Ok(x),
+        _ => Err("expected a name".to_string()),
+    }
+}
+
+// The first atom of a form and the rest of it.
+fn head(value: &Value) -> Option<(&str, &[Value])> {
+    match value {
+        Value::Form(items, _) => match items.split_first() {
+            Some((Value::Atom(x), rest)) => Some((x, rest)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The name a top-level form defines, if any.
+fn defines(value: &Value) -> Option<&str> {
+    match head(value) {
+        Some(("ROUTINE", x))
+        | Some(("OBJECT", x))
+        | Some(("ROOM", x))
+        | Some(("GLOBAL", x))
+        | Some(("CONSTANT", x)) => atom(x.first()).ok(),
+        _ => None,
+    }
+}
+
+fn is_table(name: &str) -> bool {
+    matches!(name, "TABLE" | "LTABLE" | "PTABLE" | "PLTABLE" | "ITABLE")
+}
+
+// Opcodes that store a result, with their number of operands.
+fn store_op(name: &str) -> Option<(&'static str, usize)> {
+    Some(match name {
+        "MOD" => ("MOD", 2),
+        "BCOM" => ("NOT", 1),
+        "GET" => ("LOADW", 2),
+        "GETB" => ("LOADB", 2),
+        "GETP" => ("GET_PROP", 2),
+        "GETPT" => ("GET_PROP_ADDR", 2),
+        "PTSIZE" => ("GET_PROP_LEN", 1),
+        "NEXTP" => ("GET_NEXT_PROP", 2),
+        "LOC" => ("GET_PARENT", 1),
+        "RANDOM" => ("RANDOM", 1),
+        "VALUE" => ("LOAD", 1),
+        _ => return None,
+    })
+}
+
+// Arithmetic that takes any number of operands, a pair at a time.
+fn arithmetic_op(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "+" => "ADD",
+        "-" => "SUB",
+        "*" => "MUL",
+        "/" => "DIV",
+        "BAND" | "ANDB" => "AND",
+        "BOR" | "ORB" => "OR",
+        _ => return None,
+    })
+}
+
+// Opcodes without a result, with their number of operands. Their value is true.
+fn void_op(name: &str) -> Option<(&'static str, usize)> {
+    Some(match name {
+        "PUT" => ("STOREW", 3),
+        "PUTB" => ("STOREB", 3),
+        "PUTP" => ("PUT_PROP", 3),
+        "MOVE" => ("INSERT_OBJ", 2),
+        "REMOVE" => ("REMOVE_OBJ", 1),
+        "FSET" => ("SET_ATTR", 2),
+        "FCLEAR" => ("CLEAR_ATTR", 2),
+        "PRINT" => ("PRINT_PADDR", 1),
+        "PRINTN" => ("PRINT_NUM", 1),
+        "PRINTD" => ("PRINT_OBJ", 1),
+        "PRINTB" => ("PRINT_ADDR", 1),
+        "PRINTC" => ("PRINT_CHAR", 1),
+        "CRLF" => ("NEW_LINE", 0),
+        "READ" => ("SREAD", 2),
+        "QUIT" => ("QUIT", 0),
+        "RESTART" => ("RESTART", 0),
+        "PUSH" => ("PUSH", 1),
+        _ => return None,
+    })
+}
+
+// Opcodes that branch: the opcode, whether the test is negated and the number of operands.
+fn branch_op(name: &str) -> Option<(&'static str, bool, usize, usize)> {
+    Some(match name {
+        "EQUAL?" | "==?" | "=?" => ("JE", false, 2, 4),
+        "N==?" | "N=?" => ("JE", true, 2, 4),
+        "L?" => ("JL", false, 2, 2),
+        "G?" => ("JG", false, 2, 2),
+        "L=?" => ("JG", true, 2, 2),
+        "G=?" => ("JL", true, 2, 2),
+        "0?" | "ZERO?" => ("JZ", false, 1, 1),
+        "BTST" => ("TEST", false, 2, 2),
+        "IN?" => ("JIN", false, 2, 2),
+        "FSET?" => ("TEST_ATTR", false, 2, 2),
+        "IGRTR?" => ("INC_CHK", false, 2, 2),
+        "DLESS?" => ("DEC_CHK", false, 2, 2),
+        "VERIFY" => ("VERIFY", false, 0, 0),
+        "SAVE" => ("SAVE", false, 0, 0),
+        "RESTORE" => ("RESTORE", false, 0, 0),
+        _ => return None,
+    })
+}
+
+fn is_predicate(name: &str) -> bool {
+    branch_op(name).is_some() || matches!(name, "NOT" | "T?" | "1?" | "VERB?" | "PRSO?" | "PRSI?")
+}
+
+#[derive(Debug, Clone)]
+enum Symbol {
+    Constant(i32),
+    // A global variable, by its index.
+    Global(usize),
+    Object(usize),
+    // A routine and the least and most arguments it takes.
+    Routine(usize, usize),
+    // A table or string, by its assembly label.
+    Label(String),
+    Word(String),
+}
+
+// Where the value of an expression goes.
+#[derive(Debug, Clone, PartialEq)]
+enum Dest {
+    Discard,
+    Stack,
+    Var(String),
+    Return,
+}
+
+// A REPEAT or PROG that RETURN and AGAIN refer to.
+#[derive(Clone)]
+struct Block {
+    again: String,
+    exit: String,
+    dest: Dest,
+}
+
+struct Syntax {
+    verb: String,
+    objects: usize,
+    prepositions: [Option<String>; 2],
+    action: usize,
+}
+
+// An action: its name without V-, its routine and its preaction.
+struct Action {
+    name: String,
+    routine: Option<String>,
+    preaction: Option<String>,
+}
+
+#[derive(Default)]
+struct Compiler {
+    line: usize,
+    symbols: HashMap<String, Symbol>,
+    globals: Vec<String>,
+    global_values: HashMap<String, String>,
+    objects: usize,
+    properties: HashMap<String, usize>,
+    next_property: usize,
+    next_direction: usize,
+    flags: usize,
+    directions: Vec<String>,
+    words: BTreeSet<String>,
+    word_synonyms: Vec<(String, String)>,
+    buzz: Vec<String>,
+    prepositions: Vec<String>,
+    syntaxes: Vec<Syntax>,
+    actions: Vec<Action>,
+    labels: usize,
+    strings: HashMap<String, String>,
+    inits: Vec<String>,
+    objects_out: String,
+    data: String,
+    code: String,
+    strings_out: String,
+    // The routine being compiled.
+    locals: Vec<String>,
+    temps: usize,
+    max_temps: usize,
+    blocks: Vec<Block>,
+    body: String,
+}
+
+impl Compiler {
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), String> {
+        if mangle(name) == "main" {
+            return Err(format!("{} is reserved", name));
+        }
+        if self.symbols.insert(name.to_string(), symbol).is_some() {
+            return Err(format!("{} is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("_{}", self.labels)
+    }
+
+    fn emit(&mut self, text: &str) {
+        self.body += "    ";
+        self.body += text;
+        self.body += "\n";
+    }
+
+    fn place(&mut self, label: &str) {
+        self.body += label;
+        self.body += ":\n";
+    }
+
+    fn word(&mut self, name: &str) -> String {
+        let word = name.to_lowercase();
+        self.words.insert(word.clone());
+        word
+    }
+
+    fn property(&mut self, name: &str) -> Result<usize, String> {
+        if let Some(&x) = self.properties.get(name) {
+            return Ok(x);
+        }
+        if self.next_property > self.next_direction {
+            return Err("too many properties".to_string());
+        }
+        let number = self.next_property;
+        self.next_property += 1;
+        self.properties.insert(name.to_string(), number);
+        self.define(&format!("P?{}", name), Symbol::Constant(number as i32))?;
+        Ok(number)
+    }
+
+    fn flag(&mut self, name: &str) -> Result<(), String> {
+        if self.symbols.contains_key(name) {
+            return Ok(());
+        }
+        if self.flags == NUM_FLAGS {
+            return Err("too many flags".to_string());
+        }
+        self.flags += 1;
+        self.define(name, Symbol::Constant(self.flags as i32 - 1))
+    }
+
+    fn action(&mut self, routine: &str) -> Result<usize, String> {
+        let name = routine.strip_prefix("V-").unwrap_or(routine);
+        if let Some(x) = self.actions.iter().position(|x| x.name == name) {
+            self.actions[x].routine = Some(routine.to_string());
+            return Ok(x);
+        }
+        self.actions.push(Action {
+            name: name.to_string(),
+            routine: Some(routine.to_string()),
+            preaction: None,
+        });
+        self.define(
+            &format!("V?{}", name),
+            Symbol::Constant(self.actions.len() as i32 - 1),
+        )?;
+        Ok(self.actions.len() - 1)
+    }
+
+    fn lookup(&mut self, name: &str) -> Result<Symbol, String> {
+        if let Some(x) = self.symbols.get(name) {
+            return Ok(x.clone());
+        }
+        if let Some(x) = name.strip_prefix("P?") {
+            return self.property(x).map(|x| Symbol::Constant(x as i32));
+        }
+        if let Some(x) = name.strip_prefix("W?") {
+            return Ok(Symbol::Word(self.word(x)));
+        }
+        Err(format!("{} isn't defined", name))
+    }
+
+    // The value of a constant expression, if it is one.
+    fn constant(&mut self, value: &Value) -> Option<i32> {
+        match value {
+            Value::Number(x) => Some(*x),
+            Value::Form(x, _) if x.is_empty() => Some(0),
+            Value::Atom(x) if self.local(x).is_some() => None,
+            Value::Atom(x) | Value::Global(x) => match self.lookup(x) {
+                Ok(Symbol::Constant(x)) => Some(x),
+                Ok(Symbol::Object(x)) => Some(x as i32),
+                Err(_) if x == "T" => Some(1),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn number(&self, x: i32) -> Result<String, String> {
+        if !(-32768..=65535).contains(&x) {
+            return Err(format!("{} doesn't fit in a word", x));
+        }
+        Ok(x.to_string())
+    }
+
+    fn string(&mut self, text: &str) -> String {
+        if let Some(x) = self.strings.get(text) {
+            return x.clone();
+        }
+        let label = self.label();
+        self.strings_out += &format!(".string {} {}\n", label, quote(text));
+        self.strings.insert(text.to_string(), label.clone());
+        label
+    }
+
+    fn local(&self, name: &str) -> Option<String> {
+        self.locals
+            .iter()
+            .position(|x| x == name)
+            .map(|x| format!("L{:02X}", x))
+    }
+
+    fn temp(&mut self) -> Result<String, String> {
+        let index = self.locals.len() + self.temps;
+        if index == MAX_LOCALS {
+            return Err("the routine needs more than 15 locals".to_string());
+        }
+        self.temps += 1;
+        self.max_temps = self.max_temps.max(self.temps);
+        Ok(format!("L{:02X}", index))
+    }
+
+    // The variable a name refers to, for SET, INC and the like.
+    fn variable(&mut self, value: &Value) -> Result<String, String> {
+        let (name, local) = match value {
+            Value::Atom(x) | Value::Local(x) => (x, true),
+            Value::Global(x) => (x, false),
+            _ => return Err("expected the name of a variable".to_string()),
+        };
+        if let (true, Some(x)) = (local, self.local(name)) {
+            return Ok(x);
+        }
+        match self.symbols.get(name) {
+            Some(Symbol::Global(_)) => Ok(mangle(name)),
+            _ => Err(format!("{} isn't a variable", name)),
+        }
+    }
+
+    // The operand of a value that needs no code, if it is one.
+    fn simple(&mut self, value: &Value) -> Result<Option<String>, String> {
+        Ok(Some(match value {
+            Value::Number(x) => self.number(*x)?,
+            Value::Str(x) => self.string(x),
+            Value::Local(x) => self
+                .local(x)
+                .ok_or(format!("{} isn't a local variable", x))?,
+            Value::Atom(x) if self.local(x).is_some() => self.local(x).unwrap(),
+            Value::Atom(x) | Value::Global(x) => match self.lookup(x) {
+                Ok(Symbol::Constant(x)) => self.number(x)?,
+                Ok(Symbol::Label(x)) => x,
+                Ok(Symbol::Word(x)) => format!("'{}'", x),
+                Ok(_) => mangle(x),
+                Err(_) if x == "T" => "1".to_string(),
+                Err(e) => return Err(e),
+            },
+            Value::Form(x, _) if x.is_empty() => "0".to_string(),
+            Value::Form(x, _) if matches!(x.first(), Some(Value::Atom(x)) if is_table(x)) => {
+                self.table(x, None)?
+            }
+            Value::List(_) => return Err("a list isn't a value".to_string()),
+            Value::Form(..) => return Ok(None),
+        }))
+    }
+
+    fn operand(&mut self, value: &Value) -> Result<String, String> {
+        match self.simple(value)? {
+            Some(x) => Ok(x),
+            None => {
+                self.gen(value, &Dest::Stack)?;
+                Ok("(SP)+".to_string())
+            }
+        }
+    }
+
+    // The operands of an instruction, in order. Values that need code are computed first; the
+    // last of them is left on the stack and any others go in temporary locals, which the caller
+    // frees once the instruction is written.
+    fn operands(&mut self, values: &[Value]) -> Result<Vec<String>, String> {
+        let mut out = Vec::new();
+        for x in values {
+            out.push(self.simple(x)?);
+        }
+        let pending: Vec<usize> = (0..values.len()).filter(|&i| out[i].is_none()).collect();
+        for (n, &i) in pending.iter().enumerate() {
+            if n + 1 == pending.len() {
+                self.gen(&values[i], &Dest::Stack)?;
+                out[i] = Some("(SP)+".to_string());
+            } else {
+                let temp = self.temp()?;
+                self.gen(&values[i], &Dest::Var(temp.clone()))?;
+                out[i] = Some(temp);
+            }
+        }
+        Ok(out.into_iter().map(|x| x.unwrap()).collect())
+    }
+
+    fn put(&mut self, operand: &str, dest: &Dest) {
+        match dest {
+            Dest::Discard => {}
+            Dest::Stack => self.emit(&format!("PUSH {}", operand)),
+            Dest::Var(x) if x == operand => {}
+            Dest::Var(x) => self.emit(&format!("STORE [{}],{}", x, operand)),
+            Dest::Return if operand == "1" => self.emit("RTRUE"),
+            Dest::Return if operand == "0" => self.emit("RFALSE"),
+            Dest::Return => self.emit(&format!("RET {}", operand)),
+        }
+    }
+
+    fn jump(&mut self, target: &str) {
+        match target {
+            "RTRUE" | "RFALSE" => self.emit(target),
+            _ => self.emit(&format!("JUMP {}", target)),
+        }
+    }
+
+    fn instruction(&mut self, opcode: &str, operands: &[String]) -> String {
+        if operands.is_empty() {
+            opcode.to_string()
+        } else {
+            format!("{} {}", opcode, operands.join(","))
+        }
+    }
+
+    // Writes an instruction that stores its result in dest. Those that also branch, like
+    // GET_CHILD, branch to the next instruction.
+    fn store(&mut self, opcode: &str, operands: &[String], dest: &Dest, branches: bool) {
+        let target = match dest {
+            Dest::Var(x) => x.clone(),
+            _ => "-(SP)".to_string(),
+        };
+        let mut text = format!("{} -> {}", self.instruction(opcode, operands), target);
+        if branches {
+            let next = self.label();
+            text += &format!(" [TRUE] {}", next);
+            self.emit(&text);
+            self.place(&next);
+        } else {
+            self.emit(&text);
+        }
+        match dest {
+            Dest::Discard => self.emit("POP"),
+            Dest::Return => self.emit("RET_POPPED"),
+            _ => {}
+        }
+    }
+
+    fn check_count(name: &str, args: &[Value], min: usize, max: usize) -> Result<(), String> {
+        if args.len() < min || args.len() > max {
+            return Err(if min == max {
+                format!("{} takes {} arguments", name, min)
+            } else {
+                format!("{} takes from {} to {} arguments", name, min, max)
+            });
+        }
+        Ok(())
+    }
+
+    fn seq(&mut self, values: &[Value], dest: &Dest) -> Result<(), String> {
+        match values.split_last() {
+            None => self.put("1", dest),
+            Some((last, rest)) => {
+                for x in rest {
+                    self.gen(x, &Dest::Discard)?;
+                }
+                self.gen(last, dest)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn gen(&mut self, value: &Value, dest: &Dest) -> Result<(), String> {
+        match value {
+            Value::Form(items, line)
+                if !items.is_empty() && !matches!(&items[0], Value::Atom(x) if is_table(x)) =>
+            {
+                self.line = *line;
+                self.form(&items[0], &items[1..], dest)
+            }
+            _ if *dest == Dest::Discard => Ok(()),
+            _ => {
+                let x = self.operand(value)?;
+                self.put(&x, dest);
+                Ok(())
+            }
+        }
+    }
+
+    fn form(&mut self, head: &Value, args: &[Value], dest: &Dest) -> Result<(), String> {
+        let name = match head {
+            Value::Atom(x) => x.as_str(),
+            _ => return Err("a form has to start with a name".to_string()),
+        };
+        if is_predicate(name) {
+            return self.predicate_value(head, args, dest);
+        }
+        if let Some(opcode) = arithmetic_op(name) {
+            return match args.len() {
+                0 => Err(format!("{} needs arguments", name)),
+                1 if name == "-" => self.call_op("SUB", &[Value::Number(0), args[0].clone()], dest),
+                1 => self.gen(&args[0], dest),
+                2 => self.call_op(opcode, args, dest),
+                n => {
+                    let mut first = vec![head.clone()];
+                    first.extend_from_slice(&args[..n - 1]);
+                    let pair = [Value::Form(first, self.line), args[n - 1].clone()];
+                    self.call_op(opcode, &pair, dest)
+                }
+            };
+        }
+        if let Some((opcode, count)) = store_op(name) {
+            Compiler::check_count(name, args, count, count)?;
+            return self.call_op(opcode, args, dest);
+        }
+        if let Some((opcode, count)) = void_op(name) {
+            Compiler::check_count(name, args, count, count)?;
+            let mark = self.temps;
+            let operands = self.operands(args)?;
+            let text = self.instruction(opcode, &operands);
+            self.emit(&text);
+            self.temps = mark;
+            self.put("1", dest);
+            return Ok(());
+        }
+        match name {
+            "COND" => self.cond(args, dest),
+            "AND" => self.and(head, args, dest),
+            "OR" => self.or(head, args, dest),
+            "REPEAT" => self.block(args, dest, true),
+            "PROG" | "BIND" => self.block(args, dest, false),
+            "RETURN" => {
+                Compiler::check_count(name, args, 0, 1)?;
+                let value = args
+                    .first()
+                    .cloned()
+                    .unwrap_or(Value::Atom("T".to_string()));
+                match self.blocks.last().cloned() {
+                    Some(block) => {
+                        self.gen(&value, &block.dest)?;
+                        if block.dest != Dest::Return {
+                            self.jump(&block.exit);
+                        }
+                        Ok(())
+                    }
+                    None => self.gen(&value, &Dest::Return),
+                }
+            }
+            "AGAIN" => match self.blocks.last().cloned() {
+                Some(block) => {
+                    self.jump(&block.again);
+                    Ok(())
+                }
+                None => Err("AGAIN outside REPEAT or PROG".to_string()),
+            },
+            "RTRUE" | "RFALSE" => {
+                self.emit(name);
+                Ok(())
+            }
+            "SET" | "SETG" => {
+                Compiler::check_count(name, args, 2, 2)?;
+                let variable = match (name, &args[0]) {
+                    ("SETG", Value::Atom(x)) => self.variable(&Value::Global(x.clone()))?,
+                    (_, x) => self.variable(x)?,
+                };
+                self.gen(&args[1], &Dest::Var(variable.clone()))?;
+                self.put(&variable, dest);
+                Ok(())
+            }
+            "INC" | "DEC" => {
+                Compiler::check_count(name, args, 1, 1)?;
+                let variable = self.variable(&args[0])?;
+                self.emit(&format!("{} [{}]", name, variable));
+                self.put("1", dest);
+                Ok(())
+            }
+            "FIRST?" | "NEXT?" => {
+                Compiler::check_count(name, args, 1, 1)?;
+                let opcode = if name == "FIRST?" {
+                    "GET_CHILD"
+                } else {
+                    "GET_SIBLING"
+                };
+                let mark = self.temps;
+                let operands = self.operands(args)?;
+                self.store(opcode, &operands, dest, true);
+                self.temps = mark;
+                Ok(())
+            }
+            "TELL" => self.tell(args, dest),
+            "PRINTI" | "PRINTR" => match args {
+                [Value::Str(x)] if name == "PRINTI" => {
+                    self.emit(&format!("PRINT {}", quote(x)));
+                    self.put("1", dest);
+                    Ok(())
+                }
+                [Value::Str(x)] => {
+                    self.emit(&format!("PRINT_RET {}", quote(x)));
+                    Ok(())
+                }
+                _ => Err(format!("{} takes a string", name)),
+            },
+            "APPLY" => {
+                Compiler::check_count(name, args, 1, MAX_ARGS + 1)?;
+                self.call_op("CALL", args, dest)
+            }
+            _ => match self.lookup(name) {
+                Ok(Symbol::Routine(min, max)) => {
+                    if args.len() < min || args.len() > max {
+                        return Err(format!("{} takes from {} to {} arguments", name, min, max));
+                    }
+                    let mut values = vec![Value::Global(name.to_string())];
+                    values.extend_from_slice(args);
+                    self.call_op("CALL", &values, dest)
+                }
+                _ => Err(format!("{} isn't a routine", name)),
+            },
+        }
+    }
+
+    fn call_op(&mut self, opcode: &str, args: &[Value], dest: &Dest) -> Result<(), String> {
+        let mark = self.temps;
+        let operands = self.operands(args)?;
+        self.store(opcode, &operands, dest, false);
+        self.temps = mark;
+        Ok(())
+    }
+
+    fn tell(&mut self, args: &[Value], dest: &Dest) -> Result<(), String> {
+        let mut it = args.iter();
+        while let Some(x) = it.next() {
+            let opcode = match x {
+                Value::Str(x) => {
+                    self.emit(&format!("PRINT {}", quote(x)));
+                    continue;
+                }
+                Value::Atom(x) if x == "CR" || x == "CRLF" => {
+                    self.emit("NEW_LINE");
+                    continue;
+                }
+                Value::Atom(x) if x == "D" => "PRINT_OBJ",
+                Value::Atom(x) if x == "N" => "PRINT_NUM",
+                Value::Atom(x) if x == "C" => "PRINT_CHAR",
+                Value::Atom(x) if x == "B" => "PRINT_ADDR",
+                x => {
+                    let operand = self.operand(x)?;
+                    self.emit(&format!("PRINT_PADDR {}", operand));
+                    continue;
+                }
+            };
+            let value = it.next().ok_or("TELL is missing a value to print")?;
+            let operand = self.operand(value)?;
+            self.emit(&format!("{} {}", opcode, operand));
+        }
+        self.put("1", dest);
+        Ok(())
+    }
+
+    fn cond(&mut self, clauses: &[Value], dest: &Dest) -> Result<(), String> {
+        let end = self.label();
+        let mut exhaustive = false;
+        for clause in clauses {
+            let (test, body) = match clause {
+                Value::List(x) if !x.is_empty() => (&x[0], &x[1..]),
+                _ => return Err("a COND clause has to be a list with a condition".to_string()),
+            };
+            if matches!(test, Value::Atom(x) if x == "ELSE")
+                || self.constant(test).is_some_and(|x| x != 0)
+            {
+                self.seq(body, dest)?;
+                exhaustive = true;
+                break;
+            }
+            let next = self.label();
+            self.test(test, false, &next)?;
+            self.seq(body, dest)?;
+            if *dest != Dest::Return {
+                self.jump(&end);
+            }
+            self.place(&next);
+        }
+        if !exhaustive {
+            self.put("0", dest);
+        }
+        self.place(&end);
+        Ok(())
+    }
+
+    // AND as a value is false or the value of its last argument.
+    fn and(&mut self, head: &Value, args: &[Value], dest: &Dest) -> Result<(), String> {
+        let (last, rest) = match args.split_last() {
+            None => {
+                self.put("1", dest);
+                return Ok(());
+            }
+            Some(x) => x,
+        };
+        if *dest == Dest::Discard {
+            return self.predicate_value(head, args, dest);
+        }
+        let (fail, end) = (self.label(), self.label());
+        for x in rest {
+            self.test(x, false, &fail)?;
+        }
+        self.gen(last, dest)?;
+        if *dest != Dest::Return {
+            self.jump(&end);
+        }
+        self.place(&fail);
+        self.put("0", dest);
+        self.place(&end);
+        Ok(())
+    }
+
+    // OR as a value is the first argument that isn't false.
+    fn or(&mut self, head: &Value, args: &[Value], dest: &Dest) -> Result<(), String> {
+        let (last, rest) = match args.split_last() {
+            None => {
+                self.put("0", dest);
+                return Ok(());
+            }
+            Some(x) => x,
+        };
+        if *dest == Dest::Discard {
+            return self.predicate_value(head, args, dest);
+        }
+        let end = self.label();
+        for x in rest {
+            let mark = self.temps;
+            let temp = self.temp()?;
+            self.gen(x, &Dest::Var(temp.clone()))?;
+            let next = self.label();
+            self.emit(&format!("JZ {} [TRUE] {}", temp, next));
+            self.put(&temp, dest);
+            if *dest != Dest::Return {
+                self.jump(&end);
+            }
+            self.place(&next);
+            self.temps = mark;
+        }
+        self.gen(last, dest)?;
+        self.place(&end);
+        Ok(())
+    }
+
+    // REPEAT, PROG and BIND, with their bindings.
+    fn block(&mut self, args: &[Value], dest: &Dest, repeat: bool) -> Result<(), String> {
+        let bindings = match args.first() {
+            Some(Value::List(x)) => x,
+            _ => return Err("expected a list of bindings".to_string()),
+        };
+        for x in bindings {
+            if let Value::List(x) = x {
+                let name = atom(x.first())?;
+                let local = self.local(name).unwrap();
+                if let Some(value) = x.get(1) {
+                    self.gen(value, &Dest::Var(local))?;
+                }
+            }
+        }
+        let (again, exit) = (self.label(), self.label());
+        self.place(&again);
+        self.blocks.push(Block {
+            again: again.clone(),
+            exit: exit.clone(),
+            dest: dest.clone(),
+        });
+        if repeat {
+            for x in &args[1..] {
+                self.gen(x, &Dest::Discard)?;
+            }
+            self.jump(&again);
+        } else {
+            self.seq(&args[1..], dest)?;
+        }
+        self.blocks.pop();
+        self.place(&exit);
+        Ok(())
+    }
+
+    fn predicate_value(&mut self, head: &Value, args: &[Value], dest: &Dest) -> Result<(), String> {
+        let mut items = vec![head.clone()];
+        items.extend_from_slice(args);
+        let value = Value::Form(items, self.line);
+        match dest {
+            Dest::Discard => {
+                let next = self.label();
+                self.test(&value, true, &next)?;
+                self.place(&next);
+            }
+            Dest::Return => {
+                self.test(&value, true, "RTRUE")?;
+                self.emit("RFALSE");
+            }
+            _ => {
+                let (fail, end) = (self.label(), self.label());
+                self.test(&value, false, &fail)?;
+                self.put("1", dest);
+                self.jump(&end);
+                self.place(&fail);
+                self.put("0", dest);
+                self.place(&end);
+            }
+        }
+        Ok(())
+    }
+
+    fn branch(&mut self, text: &str, sense: bool, target: &str) {
+        let sense = if sense { "TRUE" } else { "FALSE" };
+        self.emit(&format!("{} [{}] {}", text, sense, target));
+    }
+
+    // Writes code that goes to target if the value's truth is sense, and falls through if not.
+    // The target can also be RTRUE or RFALSE.
+    fn test(&mut self, value: &Value, sense: bool, target: &str) -> Result<(), String> {
+        if let Some(x) = self.constant(value) {
+            if (x != 0) == sense {
+                self.jump(target);
+            }
+            return Ok(());
+        }
+        let (name, args) = match head(value) {
+            Some(x) => x,
+            None => return self.test_value(value, sense, target),
+        };
+        if let Value::Form(_, line) = value {
+            self.line = *line;
+        }
+        let line = self.line;
+        let form = |name: &str, first: Value, rest: &[Value]| {
+            let mut items = vec![Value::Atom(name.to_string()), first];
+            items.extend_from_slice(rest);
+            Value::Form(items, line)
+        };
+        match name {
+            "NOT" => {
+                Compiler::check_count(name, args, 1, 1)?;
+                self.test(&args[0], !sense, target)
+            }
+            "T?" => {
+                Compiler::check_count(name, args, 1, 1)?;
+                self.test(&args[0], sense, target)
+            }
+            "AND" | "OR" if args.is_empty() => {
+                self.test(&Value::Number((name == "AND") as i32), sense, target)
+            }
+            "AND" | "OR" => {
+                // With sense true, AND goes to the target only after its last test; with sense
+                // false it goes at the first one that fails. OR is the same with tests negated.
+                let all = (name == "AND") == sense;
+                if all {
+                    let skip = self.label();
+                    for x in &args[..args.len() - 1] {
+                        self.test(x, !sense, &skip)?;
+                    }
+                    self.test(&args[args.len() - 1], sense, target)?;
+                    self.place(&skip);
+                } else {
+                    for x in args {
+                        self.test(x, sense, target)?;
+                    }
+                }
+                Ok(())
+            }
+            "1?" => {
+                Compiler::check_count(name, args, 1, 1)?;
+                self.test(
+                    &form("EQUAL?", args[0].clone(), &[Value::Number(1)]),
+                    sense,
+                    target,
+                )
+            }
+            "VERB?" | "PRSO?" | "PRSI?" => {
+                let mut values = Vec::new();
+                for x in args {
+                    values.push(match (name, x) {
+                        ("VERB?", Value::Atom(x)) => Value::Global(format!("V?{}", x)),
+                        (_, x) => x.clone(),
+                    });
+                }
+                let subject = Value::Global(
+                    match name {
+                        "VERB?" => "PRSA",
+                        "PRSO?" => "PRSO",
+                        _ => "PRSI",
+                    }
+                    .to_string(),
+                );
+                self.test(&form("EQUAL?", subject, &values), sense, target)
+            }
+            "FIRST?" | "NEXT?" => {
+                Compiler::check_count(name, args, 1, 1)?;
+                let opcode = if name == "FIRST?" {
+                    "GET_CHILD"
+                } else {
+                    "GET_SIBLING"
+                };
+                let mark = self.temps;
+                let temp = self.temp()?;
+                let operands = self.operands(args)?;
+                let text = format!("{} -> {}", self.instruction(opcode, &operands), temp);
+                self.branch(&text, sense, target);
+                self.temps = mark;
+                Ok(())
+            }
+            "EQUAL?" | "==?" | "=?" | "N==?" | "N=?" if args.len() > 4 => {
+                // JE compares with at most three values, so longer lists take several.
+                let mark = self.temps;
+                let first = match self.simple(&args[0])? {
+                    Some(x) => x,
+                    None => {
+                        let temp = self.temp()?;
+                        self.gen(&args[0], &Dest::Var(temp.clone()))?;
+                        temp
+                    }
+                };
+                let any = sense != name.starts_with('N');
+                let skip = self.label();
+                for x in args[1..].chunks(3) {
+                    let mut operands = vec![first.clone()];
+                    operands.extend(self.operands(x)?);
+                    let text = self.instruction("JE", &operands);
+                    self.branch(&text, true, if any { target } else { &skip });
+                }
+                if !any {
+                    self.jump(target);
+                }
+                self.place(&skip);
+                self.temps = mark;
+                Ok(())
+            }
+            _ => match branch_op(name) {
+                Some((opcode, negated, min, max)) => {
+                    Compiler::check_count(name, args, min, max)?;
+                    let mark = self.temps;
+                    let operands = if opcode == "INC_CHK" || opcode == "DEC_CHK" {
+                        let variable = self.variable(&args[0])?;
+                        let mut x = vec![format!("[{}]", variable)];
+                        x.extend(self.operands(&args[1..])?);
+                        x
+                    } else {
+                        self.operands(args)?
+                    };
+                    let text = self.instruction(opcode, &operands);
+                    self.branch(&text, sense != negated, target);
+                    self.temps = mark;
+                    Ok(())
+                }
+                None => self.test_value(value, sense, target),
+            },
+        }
+    }
+
+    fn test_value(&mut self, value: &Value, sense: bool, target: &str) -> Result<(), String> {
+        let mark = self.temps;
+        let operand = self.operand(value)?;
+        self.branch(&format!("JZ {}", operand), !sense, target);
+        self.temps = mark;
+        Ok(())
+    }
+
+    // A table's data, returning its label.
+    fn table(&mut self, items: &[Value], label: Option<String>) -> Result<String, String> {
+        let name = atom(items.first())?;
+        let mut args = &items[1..];
+        let mut prefix = None;
+        if name == "ITABLE" {
+            if let Some(Value::Atom(x)) = args.first() {
+                if matches!(x.as_str(), "NONE" | "BYTE" | "WORD") {
+                    prefix = Some(x.clone()).filter(|x| x != "NONE");
+                    args = &args[1..];
+                }
+            }
+        }
+        let count = match (name, args.first()) {
+            ("ITABLE", Some(x)) => {
+                args = &args[1..];
+                Some(self.constant(x).ok_or("ITABLE needs a constant length")?)
+            }
+            ("ITABLE", None) => return Err("ITABLE needs a length".to_string()),
+            _ => None,
+        };
+        let mut bytes = false;
+        let mut length = name == "LTABLE" || name == "PLTABLE";
+        if let Some(Value::List(flags)) = args.first() {
+            for x in flags {
+                match atom(Some(x))? {
+                    "BYTE" => bytes = true,
+                    "LENGTH" => length = true,
+                    "WORD" | "PURE" => {}
+                    x => return Err(format!("unknown table flag {}", x)),
+                }
+            }
+            args = &args[1..];
+        }
+
+        let label = label.unwrap_or_else(|| self.label());
+        let mut line = format!(".table {}", label);
+        let mut space = 0;
+        match count {
+            Some(count) => {
+                if count < 0 {
+                    return Err("a table can't have a negative length".to_string());
+                }
+                match prefix.as_deref() {
+                    Some("BYTE") => line += &format!(" #{:02x}", count & 0xff),
+                    Some(_) => line += &format!(" #{:04x}", count),
+                    None => {}
+                }
+                let zero = [Value::Number(0)];
+                let values = if args.is_empty() { &zero[..] } else { args };
+                if values.iter().all(|x| *x == Value::Number(0)) {
+                    space = count as usize * if bytes { 1 } else { 2 };
+                } else {
+                    for x in values.iter().cycle().take(count as usize) {
+                        line += " ";
+                        line += &self.datum(x, bytes)?;
+                    }
+                }
+            }
+            None => {
+                if length {
+                    let n = args.len() as i32;
+                    line += &if bytes {
+                        format!(" #{:02x}", n)
+                    } else {
+                        format!(" #{:04x}", n)
+                    };
+                }
+                for x in args {
+                    line += " ";
+                    line += &self.datum(x, bytes)?;
+                }
+            }
+        }
+        self.data += &line;
+        self.data += "\n";
+        if space > 0 {
+            self.data += &format!(".space {}\n", space);
+        }
+        Ok(label)
+    }
+
+    // An element of a table or property, as a byte or a word.
+    fn datum(&mut self, value: &Value, byte: bool) -> Result<String, String> {
+        if let Some(x) = self.constant(value) {
+            return Ok(match byte {
+                true if (-128..=255).contains(&x) => format!("#{:02x}", x & 0xff),
+                true => return Err(format!("{} doesn't fit in a byte", x)),
+                false => {
+                    self.number(x)?;
+                    format!("#{:04x}", x & 0xffff)
+                }
+            });
+        }
+        if let Value::Atom(x) | Value::Global(x) = value {
+            if let Ok(Symbol::Global(_)) = self.lookup(x) {
+                return Err(format!("the variable {} can't go in a table", x));
+            }
+        }
+        match self.simple(value)? {
+            Some(_) if byte => Err("addresses don't fit in a byte".to_string()),
+            Some(x) => Ok(x),
+            None => Err("only constants can go in a table".to_string()),
+        }
+    }
+
+    // Locals bound by REPEAT, PROG and BIND, which get slots in the routine's header.
+    fn bindings(&self, value: &Value, locals: &mut Vec<String>) -> Result<(), String> {
+        if let Some((name, args)) = head(value) {
+            if matches!(name, "REPEAT" | "PROG" | "BIND") {
+                if let Some(Value::List(x)) = args.first() {
+                    for x in x {
+                        let name = match x {
+                            Value::Atom(x) => x,
+                            Value::List(x) => atom(x.first())?,
+                            _ => return Err("expected a binding".to_string()),
+                        };
+                        if !locals.iter().any(|x| x == name) {
+                            locals.push(name.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        if let Value::Form(x, _) | Value::List(x) = value {
+            for x in x {
+                self.bindings(x, locals)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn routine(&mut self, args: &[Value]) -> Result<(), String> {
+        let name = atom(args.first())?;
+        let params = match args.get(1) {
+            Some(Value::List(x)) => x,
+            _ => return Err(format!("ROUTINE {} needs a list of arguments", name)),
+        };
+        let body = &args[2..];
+
+        // Defaults of optional arguments have to be constants, as they live in the header.
+        let mut locals = Vec::new();
+        let mut header = Vec::new();
+        let mut aux = Vec::new();
+        let mut mode = "";
+        for x in params {
+            match x {
+                Value::Str(x) => match x.as_str() {
+                    "OPT" | "OPTIONAL" => mode = "OPT",
+                    "AUX" | "EXTRA" => mode = "AUX",
+                    x => return Err(format!("\"{}\" arguments aren't supported", x)),
+                },
+                Value::Atom(x) => {
+                    locals.push(x.clone());
+                    header.push(0);
+                }
+                Value::List(x) if !mode.is_empty() => {
+                    let local = atom(x.first())?;
+                    let value = x.get(1).ok_or(format!("{} needs a default value", local))?;
+                    match self.constant(value) {
+                        Some(x) => header.push(x),
+                        None if mode == "AUX" => {
+                            header.push(0);
+                            aux.push((locals.len(), value.clone()));
+                        }
+                        None => return Err(format!("the default of {} must be a constant", local)),
+                    }
+                    locals.push(local.to_string());
+                }
+                _ => return Err("expected an argument".to_string()),
+            }
+        }
+        for x in body {
+            self.bindings(x, &mut locals)?;
+        }
+        if locals.len() > MAX_LOCALS {
+            return Err(format!("ROUTINE {} has more than 15 locals", name));
+        }
+
+        self.locals = locals;
+        self.temps = 0;
+        self.max_temps = 0;
+        self.blocks.clear();
+        self.body.clear();
+        for (i, value) in aux {
+            self.gen(&value, &Dest::Var(format!("L{:02X}", i)))?;
+        }
+        self.seq(body, &Dest::Return)?;
+        let count = self.locals.len() + self.max_temps;
+        while header.last() == Some(&0) {
+            header.pop();
+        }
+        let mut line = format!(".routine {} {}", mangle(name), count);
+        for x in header {
+            line += &format!(" {}", x);
+        }
+        self.code += &line;
+        self.code += "\n";
+        self.code += &self.body;
+        self.code += "\n";
+        Ok(())
+    }
+
+    fn object(&mut self, args: &[Value]) -> Result<(), String> {
+        let name = atom(args.first())?;
+        let mut description = String::new();
+        let mut parent = None;
+        let mut flags = Vec::new();
+        let mut properties = Vec::new();
+        for x in &args[1..] {
+            let items = match x {
+                Value::List(x) if !x.is_empty() => x,
+                _ => return Err(format!("properties of {} have to be lists", name)),
+            };
+            let property = atom(items.first())?;
+            let values = &items[1..];
+            match property {
+                "DESC" => match values {
+                    [Value::Str(x)] => description = x.clone(),
+                    _ => return Err("DESC takes a string".to_string()),
+                },
+                "IN" | "LOC" => {
+                    let x = match values {
+                        [Value::Atom(x)] | [Value::Global(x)] => x,
+                        _ => return Err(format!("{} takes an object", property)),
+                    };
+                    match self.lookup(x)? {
+                        Symbol::Object(_) => parent = Some(mangle(x)),
+                        _ => return Err(format!("{} isn't an object", x)),
+                    }
+                }
+                "FLAGS" => {
+                    for x in values {
+                        let flag = self.constant(x).ok_or("FLAGS takes flag names")?;
+                        flags.push(flag.to_string());
+                    }
+                }
+                "SYNONYM" | "ADJECTIVE" => {
+                    if values.len() > 4 {
+                        return Err(format!("an object has at most 4 words in {}", property));
+                    }
+                    let mut data = Vec::new();
+                    for x in values {
+                        data.push(format!("'{}'", self.word(atom(Some(x))?)));
+                    }
+                    properties.push((self.property(property)?, data));
+                }
+                x if self.directions.iter().any(|d| d == x) => {
+                    let data = self.exit(values)?;
+                    properties.push((self.properties[x], data));
+                }
+                _ => {
+                    let mut data = Vec::new();
+                    for x in values {
+                        data.push(self.datum(x, false)?);
+                    }
+                    properties.push((self.property(property)?, data));
+                }
+            }
+        }
+        let mut line = format!(".object {} {}", mangle(name), quote(&description));
+        if let Some(x) = parent {
+            line += &format!(" {}", x);
+        }
+        self.objects_out += &line;
+        self.objects_out += "\n";
+        if !flags.is_empty() {
+            self.objects_out += &format!(".attr {}\n", flags.join(" "));
+        }
+        for (number, data) in properties {
+            if data.is_empty() {
+                continue;
+            }
+            self.objects_out += &format!(".prop {} {}\n", number, data.join(" "));
+        }
+        Ok(())
+    }
+
+    // The value of an exit: TO room is one byte, a string of why not two, PER routine three and
+    // TO room IF flag [ELSE string] four.
+    fn exit(&mut self, values: &[Value]) -> Result<Vec<String>, String> {
+        let object = |compiler: &mut Compiler, x: &Value| match x {
+            Value::Atom(name) => match compiler.lookup(name) {
+                Ok(Symbol::Object(x)) => Ok(format!("#{:02x}", x)),
+                _ => Err(format!("{} isn't an object", name)),
+            },
+            _ => Err("expected an object".to_string()),
+        };
+        let keyword = |x: &Value| match x {
+            Value::Atom(x) => x.clone(),
+            _ => String::new(),
+        };
+        match values {
+            [Value::Str(x)] => Ok(vec![self.string(x)]),
+            [k, Value::Str(x)] if keyword(k) == "SORRY" => Ok(vec![self.string(x)]),
+            [k, room] if keyword(k) == "TO" => Ok(vec![object(self, room)?]),
+            [k, routine] if keyword(k) == "PER" => match routine {
+                Value::Atom(x) if matches!(self.lookup(x), Ok(Symbol::Routine(..))) => {
+                    Ok(vec![mangle(x), "#00".to_string()])
+                }
+                _ => Err("PER takes a routine".to_string()),
+            },
+            [k, room, i, flag, rest @ ..] if keyword(k) == "TO" && keyword(i) == "IF" => {
+                let flag = match flag {
+                    Value::Atom(x) => match self.lookup(x) {
+                        Ok(Symbol::Global(x)) => format!("#{:02x}", 0x10 + x),
+                        _ => return Err(format!("{} isn't a global", x)),
+                    },
+                    _ => return Err("IF takes a global".to_string()),
+                };
+                let message = match rest {
+                    [] => "#0000".to_string(),
+                    [e, Value::Str(x)] if keyword(e) == "ELSE" => self.string(x),
+                    _ => return Err("expected ELSE and a string".to_string()),
+                };
+                Ok(vec![object(self, room)?, flag, message])
+            }
+            _ => Err("can't read the exit".to_string()),
+        }
+    }
+
+    fn syntax(&mut self, args: &[Value]) -> Result<(), String> {
+        let verb = self.word(atom(args.first())?);
+        let mut objects = 0;
+        let mut prepositions = [None, None];
+        let mut rest = args[1..].iter();
+        loop {
+            match rest.next() {
+                Some(Value::Atom(x)) if x == "=" => break,
+                Some(Value::Atom(x)) if x == "OBJECT" => {
+                    if objects == 2 {
+                        return Err("a syntax has at most two objects".to_string());
+                    }
+                    objects += 1;
+                }
+                // FIND and the search flags aren't used by the library's parser.
+                Some(Value::List(_)) => {}
+                Some(Value::Atom(x)) => {
+                    let word = self.word(x);
+                    if prepositions[objects.min(1)].is_some() || objects == 2 {
+                        return Err(format!("misplaced preposition {}", x));
+                    }
+                    if !self.prepositions.contains(&word) {
+                        self.prepositions.push(word.clone());
+                    }
+                    prepositions[objects.min(1)] = Some(word);
+                }
+                _ => return Err("expected = and an action".to_string()),
+            }
+        }
+        let routine = atom(rest.next())?;
+        let action = self.action(routine)?;
+        if let Some(x) = rest.next() {
+            self.actions[action].preaction = Some(atom(Some(x))?.to_string());
+        }
+        self.syntaxes.push(Syntax {
+            verb,
+            objects,
+            prepositions,
+            action,
+        });
+        Ok(())
+    }
+
+    // Names every top-level definition, so that code can refer to ones further on.
+    fn declare(&mut self, form: &Value) -> Result<(), String> {
+        let (name, args) = match (form, head(form)) {
+            (Value::Str(_), _) => return Ok(()),
+            (_, Some(x)) => x,
+            _ => return Err("expected a top-level form".to_string()),
+        };
+        match name {
+            "VERSION" => match args {
+                [Value::Atom(x)] if x == "ZIP" => {}
+                [Value::Number(3)] => {}
+                _ => return Err("only version 3 (ZIP) stories are supported".to_string()),
+            },
+            "CONSTANT" => {
+                let name = atom(args.first())?;
+                let value = args.get(1).ok_or("CONSTANT needs a value")?;
+                let symbol = match self.constant(value) {
+                    Some(x) => Symbol::Constant(x),
+                    None => match value {
+                        Value::Str(x) => Symbol::Label(self.string(x)),
+                        x if head(x).is_some_and(|(x, _)| is_table(x)) => {
+                            Symbol::Label(mangle(name))
+                        }
+                        _ => return Err(format!("the value of {} isn't a constant", name)),
+                    },
+                };
+                self.define(name, symbol)?;
+            }
+            "GLOBAL" => {
+                let name = atom(args.first())?;
+                self.define(name, Symbol::Global(self.globals.len()))?;
+                self.globals.push(name.to_string());
+            }
+            "ROUTINE" => {
+                let name = atom(args.first())?;
+                let (mut min, mut max, mut optional) = (0, 0, false);
+                if let Some(Value::List(x)) = args.get(1) {
+                    for x in x {
+                        match x {
+                            Value::Str(x) if x == "AUX" || x == "EXTRA" => break,
+                            Value::Str(_) => optional = true,
+                            _ => {
+                                max += 1;
+                                if !optional {
+                                    min += 1;
+                                }
+                            }
+                        }
+                    }
+                }
+                if max > MAX_ARGS {
+                    return Err(format!("ROUTINE {} takes more than 3 arguments", name));
+                }
+                self.define(name, Symbol::Routine(min, max))?;
+            }
+            "OBJECT" | "ROOM" => {
+                if self.objects == 255 {
+                    return Err("too many objects".to_string());
+                }
+                self.objects += 1;
+                self.define(atom(args.first())?, Symbol::Object(self.objects))?;
+            }
+            "DIRECTIONS" => {
+                for x in args {
+                    let name = atom(Some(x))?;
+                    if self.next_direction < self.next_property {
+                        return Err("too many properties".to_string());
+                    }
+                    self.properties
+                        .insert(name.to_string(), self.next_direction);
+                    let number = Symbol::Constant(self.next_direction as i32);
+                    self.define(&format!("P?{}", name), number)?;
+                    self.next_direction -= 1;
+                    self.directions.push(name.to_string());
+                    self.word(name);
+                }
+            }
+            "SYNTAX" => self.syntax(args)?,
+            "SYNONYM" => {
+                let word = self.word(atom(args.first())?);
+                for x in &args[1..] {
+                    let alias = self.word(atom(Some(x))?);
+                    self.word_synonyms.push((alias, word.clone()));
+                }
+            }
+            "BUZZ" => {
+                for x in args {
+                    let word = self.word(atom(Some(x))?);
+                    self.buzz.push(word);
+                }
+            }
+            "INSERT-FILE" => return Err("INSERT-FILE isn't supported".to_string()),
+            _ => return Err(format!("unknown top-level form {}", name)),
+        }
+        Ok(())
+    }
+
+    // Numbers the flags of objects, and flags that only code sets or tests.
+    fn allocate(&mut self, form: &Value) -> Result<(), String> {
+        match head(form) {
+            Some(("OBJECT", args)) | Some(("ROOM", args)) => {
+                for x in &args[1..] {
+                    if let Value::List(x) = x {
+                        if let Some(Value::Atom(property)) = x.first() {
+                            if property == "FLAGS" {
+                                for x in &x[1..] {
+                                    self.flag(atom(Some(x))?)?;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Some(("ROUTINE", args)) => self.code_flags(args)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn code_flags(&mut self, values: &[Value]) -> Result<(), String> {
+        for x in values {
+            if let Some((name, args)) = head(x) {
+                if matches!(name, "FSET" | "FCLEAR" | "FSET?") {
+                    if let Some(Value::Atom(x)) | Some(Value::Global(x)) = args.get(1) {
+                        self.flag(x)?;
+                    }
+                }
+            }
+            if let Value::Form(x, _) | Value::List(x) = x {
+                self.code_flags(x)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn top_level(&mut self, form: &Value) -> Result<(), String> {
+        let (name, args) = match head(form) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        match name {
+            "CONSTANT" => {
+                let name = atom(args.first())?;
+                if let (Some(Value::Form(items, _)), Some(Symbol::Label(label))) =
+                    (args.get(1), self.symbols.get(name).cloned())
+                {
+                    self.table(items, Some(label))?;
+                }
+            }
+            "GLOBAL" => {
+                let name = atom(args.first())?;
+                let value = args.get(1).cloned().unwrap_or(Value::Number(0));
+                let initial = match self.constant(&value) {
+                    Some(x) => self.number(x)?,
+                    None => {
+                        let operand = self.simple(&value)?.ok_or(format!(
+                            "the value of {} has to be a constant, string or table",
+                            name
+                        ))?;
+                        self.inits
+                            .push(format!("STORE [{}],{}", mangle(name), operand));
+                        "0".to_string()
+                    }
+                };
+                self.global_values.insert(name.to_string(), initial);
+            }
+            "ROUTINE" => self.routine(args)?,
+            "OBJECT" | "ROOM" => self.object(args)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn word_table(&self, name: &str, words: &[String]) -> String {
+        let mut line = format!(".table {} #{:04x}", name, words.len());
+        for x in words {
+            line += &format!(" '{}'", x);
+        }
+        line + "\n"
+    }
+
+    // The tables the library's parser reads, and the rest of the assembly.
+    fn finish(self) -> Result<String, String> {
+        let mut tables = String::new();
+        let mut actions = String::from(".table actions");
+        let mut preactions = String::from(".table preactions");
+        for (i, action) in self.actions.iter().enumerate() {
+            let routine = match (&action.routine, i) {
+                (Some(x), _) => Some(x.clone()),
+                // Directions work once the game has a V-WALK, even without a syntax for it.
+                (None, 0) if self.symbols.contains_key("V-WALK") => Some("V-WALK".to_string()),
+                (None, _) => None,
+            };
+            for (line, routine) in [
+                (&mut actions, routine),
+                (&mut preactions, action.preaction.clone()),
+            ] {
+                match routine {
+                    Some(x) => match self.symbols.get(&x) {
+                        Some(Symbol::Routine(..)) => *line += &format!(" {}", mangle(&x)),
+                        _ => return Err(format!("the action routine {} isn't defined", x)),
+                    },
+                    None => *line += " #0000",
+                }
+            }
+        }
+        tables += &actions;
+        tables += "\n";
+        tables += &preactions;
+        tables += "\n";
+
+        let mut syntaxes = format!(".table verb_syntaxes #{:04x}", self.syntaxes.len());
+        for x in self.syntaxes.iter() {
+            syntaxes += &format!(" '{}' #{:04x}", x.verb, x.objects);
+            for p in x.prepositions.iter() {
+                match p {
+                    Some(p) => syntaxes += &format!(" '{}'", p),
+                    None => syntaxes += " #0000",
+                }
+            }
+            syntaxes += &format!(" #{:04x}", x.action);
+        }
+        tables += &syntaxes;
+        tables += "\n";
+        tables += &self.word_table("prepositions", &self.prepositions);
+        tables += &self.word_table("buzz_words", &self.buzz);
+        let mut directions = format!(".table direction_words #{:04x}", self.directions.len());
+        for x in self.directions.iter() {
+            directions += &format!(" '{}' #{:04x}", x.to_lowercase(), self.properties[x]);
+        }
+        tables += &directions;
+        tables += "\n";
+        let mut synonyms = format!(".table word_synonyms #{:04x}", self.word_synonyms.len());
+        for (alias, word) in self.word_synonyms.iter() {
+            synonyms += &format!(" '{}' '{}'", alias, word);
+        }
+        tables += &synonyms;
+        tables += "\n";
+
+        if !matches!(self.symbols.get("GO"), Some(Symbol::Routine(0, _))) {
+            return Err("there is no GO routine without arguments".to_string());
+        }
+        let mut out = String::from("; Compiled from ZIL.\n\n.separators \".,\\\"\"\n\n");
+        for x in self.globals.iter() {
+            out += &format!(".global {} {}\n", mangle(x), self.global_values[x]);
+        }
+        out += "\n";
+        out += &self.objects_out;
+        out += "\n";
+        out += &self.data;
+        out += &tables;
+        out += "\n";
+        let words: Vec<String> = self.words.iter().cloned().collect();
+        for x in words.chunks(10) {
+            out += &format!(".words {}\n", x.join(" "));
+        }
+        out += "\n.routine main\n";
+        for x in self.inits.iter() {
+            out += &format!("    {}\n", x);
+        }
+        out += "    CALL go -> -(SP)\n    QUIT\n\n";
+        out += &self.code;
+        out += &self.strings_out;
+        Ok(out)
+    }
+}
+
+// Compiles a ZIL game to assembly for assemble().
+pub fn compile(source: &str) -> Result<String, String> {
+    let game = read(source)?;
+    let library = read(LIBRARY).map_err(|e| format!("library {}", e))?;
+    let defined: HashSet<&str> = game.iter().filter_map(defines).collect();
+    let mut forms: Vec<(&Value, &str)> = game.iter().map(|x| (x, "")).collect();
+    for x in library.iter() {
+        if defines(x).is_none_or(|x| !defined.contains(x)) {
+            forms.push((x, "library "));
+        }
+    }
+
+    let mut compiler = Compiler {
+        next_property: 1,
+        next_direction: NUM_PROPERTIES,
+        ..Default::default()
+    };
+    compiler.actions.push(Action {
+        name: "WALK".to_string(),
+        routine: None,
+        preaction: None,
+    });
+    let builtins = [
+        ("V?WALK", Symbol::Constant(0)),
+        ("ACTIONS", Symbol::Label("actions".to_string())),
+        ("PREACTIONS", Symbol::Label("preactions".to_string())),
+        ("VERB-SYNTAXES", Symbol::Label("verb_syntaxes".to_string())),
+        ("PREPOSITIONS", Symbol::Label("prepositions".to_string())),
+        ("BUZZ-WORDS", Symbol::Label("buzz_words".to_string())),
+        (
+            "DIRECTION-WORDS",
+            Symbol::Label("direction_words".to_string()),
+        ),
+        ("WORD-SYNONYMS", Symbol::Label("word_synonyms".to_string())),
+    ];
+    for (name, symbol) in builtins.iter() {
+        compiler.define(name, symbol.clone())?;
+    }
+    for x in ["SYNONYM", "ADJECTIVE", "ACTION"].iter() {
+        compiler.property(x)?;
+    }
+
+    let line = |value: &Value| match value {
+        Value::Form(_, x) => *x,
+        _ => 0,
+    };
+    for pass in 0..3 {
+        for &(form, origin) in forms.iter() {
+            compiler.line = line(form);
+            let result = match pass {
+                0 => compiler.declare(form),
+                1 => compiler.allocate(form),
+                _ => compiler.top_level(form),
+            };
+            result.map_err(|e| format!("{}line {}: {}", origin, compiler.line, e))?;
+        }
+        if pass == 0 {
+            let objects = compiler.objects as i32;
+            compiler.define("LAST-OBJECT", Symbol::Constant(objects))?;
+            // The status line shows the first three globals.
+            let order = |x: &String| ["HERE", "SCORE", "MOVES"].iter().position(|y| y == x);
+            compiler.globals.sort_by_key(|x| order(x).unwrap_or(3));
+            for (i, x) in compiler.globals.iter().enumerate() {
+                compiler.symbols.insert(x.clone(), Symbol::Global(i));
+            }
+        }
+    }
+    compiler.finish()
+}
@@ -0,0 +1,125 @@
+use memory::Memory;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct ZString {
+    pub(crate) offset: usize,
+    pub(crate) length: usize,
+    pub(crate) contents: String,
+}
+
+pub enum ZStringShift {
+    Zero,
+    One,
+    Two,
+}
+
+impl ZString {
+    pub fn with_bytes(memory: &Memory, offset: usize, length: usize, bytes: &[u8]) -> ZString {
+        ZString {
+            offset,
+            length,
+            contents: ZString::decode(memory, bytes, true),
+        }
+    }
+
+    // Abbreviations can't themselves use abbreviations, so expanding one never recurses further.
+    fn decode(memory: &Memory, bytes: &[u8], abbreviations: bool) -> String {
+        let mut shift = ZStringShift::Zero;
+        let mut contents = String::new();
+        let mut it = bytes.iter();
+        while let Some(c) = it.next() {
+            match *c {
+                0 => contents.push(' '),
+                1..=3 => {
+                    let abbrev = match it.next() {
+                        Some(&x) => x as usize,
+                        None => break,
+                    };
+                    if abbreviations {
+                        let table = memory.read_u16(0x18) as usize;
+                        let index = 32 * (*c as usize - 1) + abbrev;
+                        let offset = memory.read_u16(table + index * 2) as usize * 2;
+                        let (_, bytes) = ZString::read(memory, offset, None);
+                        contents += &ZString::decode(memory, &bytes, false);
+                    }
+                }
+                4 => shift = ZStringShift::One,
+                5 => shift = ZStringShift::Two,
+                _ => {
+                    match shift {
+                        ZStringShift::Two if *c == 6 => {
+                            let (high, low) = match (it.next(), it.next()) {
+                                (Some(&high), Some(&low)) => (high as u32, low as u32),
+                                _ => break,
+                            };
+                            let zscii = (high << 5) | (low & 0x1f);
+                            contents.push(std::char::from_u32(zscii).unwrap_or('?'));
+                        }
+                        _ => {
+                            let alphabet = match shift {
+                                ZStringShift::Zero => "______abcdefghijklmnopqrstuvwxyz",
+                                ZStringShift::One => "______ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+                                ZStringShift::Two => "______^\n0123456789.,!?_#\'\"/\\-:()",
+                            };
+                            contents.push(alphabet.as_bytes()[*c as usize] as char)
+                        }
+                    }
+                    shift = ZStringShift::Zero;
+                }
+            }
+        }
+        contents
+    }
+
+    // The length in bytes and the five bit characters of the string at offset, reading at most
+    // max_length bytes. A string missing its end bit stops at the end of memory.
+    fn read(memory: &Memory, offset: usize, max_length: Option<usize>) -> (usize, Vec<u8>) {
+        let mut length = 0usize;
+        let mut bytes: Vec<u8> = Vec::new();
+        loop {
+            if Some(length) == max_length || offset + length >= memory.len() {
+                break;
+            }
+            let x = memory.read_u16(offset + length);
+            length += 2;
+
+            bytes.push(((x >> 10) & 0x1f) as u8);
+            bytes.push(((x >> 5) & 0x1f) as u8);
+            bytes.push((x & 0x1f) as u8);
+
+            if (x & 0x8000) != 0 {
+                break;
+            }
+        }
+        (length, bytes)
+    }
+
+    pub fn new(memory: &Memory, offset: usize) -> ZString {
+        let (length, bytes) = ZString::read(memory, offset, None);
+        ZString::with_bytes(memory, offset, length, &bytes)
+    }
+
+    pub fn with_max_length(memory: &Memory, offset: usize, max_length: usize) -> ZString {
+        let (length, bytes) = ZString::read(memory, offset, Some(max_length));
+        ZString::with_bytes(memory, offset, length, &bytes)
+    }
+
+    // True if a string starting at offset is terminated before the end of memory.
+    pub fn fits(memory: &Memory, offset: usize) -> bool {
+        let mut offset = offset;
+        while offset + 1 < memory.len() {
+            if (memory.read_u16(offset) & 0x8000) != 0 {
+                return true;
+            }
+            offset += 2;
+        }
+        false
+    }
+}
+
+impl fmt::Display for ZString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.contents)
+    }
+}
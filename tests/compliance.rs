@@ -0,0 +1,181 @@
+// Runs interpreter compliance stories through the headless I/O and checks the summaries they
+// print.
+//
+// The stories aren't distributed with rustzork. Put them in tests/stories (see the README there) or
+// point RUSTZORK_CZECH, RUSTZORK_PRAXIX or RUSTZORK_ETUDE at them. A test whose story isn't in
+// tests/stories is skipped with a note on stderr, but one named by its variable has to be there.
+// Stories for a later version of the Z-machine than rustzork runs are skipped too.
+//
+//     cargo test --test compliance
+
+extern crate rustzork;
+
+use rustzork::{Header, Machine, Memory, StepResult, ZIO};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const SUPPORTED_VERSION: u8 = 3;
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Suite {
+    name: &'static str,
+    var: &'static str,
+    file: &'static str,
+    commands: &'static [&'static str],
+    check: fn(&str) -> Result<(), String>,
+}
+
+// CZECH ends with "Passed: N, Failed: N, Print tests: N" and, if it got that far,
+// "Didn't crash: hooray!".
+fn check_czech(output: &str) -> Result<(), String> {
+    let summary = output
+        .lines()
+        .find(|x| x.starts_with("Passed: "))
+        .ok_or("no summary")?;
+    let count = |label: &str| {
+        summary
+            .split(", ")
+            .find_map(|x| x.strip_prefix(label))
+            .and_then(|x| x.trim().parse::<usize>().ok())
+            .ok_or(format!("can't read {:?} from {:?}", label, summary))
+    };
+    let (passed, failed) = (count("Passed: ")?, count("Failed: ")?);
+    if failed != 0 {
+        return Err(format!("{} passed, {} failed", passed, failed));
+    }
+    if !output.contains("Didn't crash") {
+        return Err("stopped before the end of the tests".to_string());
+    }
+    Ok(())
+}
+
+fn check_praxix(output: &str) -> Result<(), String> {
+    if output.contains("All tests passed") {
+        Ok(())
+    } else {
+        Err("no \"All tests passed\" summary".to_string())
+    }
+}
+
+// TerpEtude's checks are meant to be read by a person, so all that is checked automatically is
+// that the interpreter gets through them without faulting.
+fn check_etude(output: &str) -> Result<(), String> {
+    if output.contains("Backtrace") {
+        Err("interpreter fault".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Runs the story until it quits or waits for input after the last command, and returns its
+// output. Runs on another thread so that a story that never stops fails instead of hanging.
+fn play(story: Vec<u8>, commands: &'static [&'static str]) -> Result<String, String> {
+    let (send, receive) = mpsc::channel();
+    thread::spawn(move || {
+        let memory = Memory::new(&story);
+        let header = Header::new(&memory);
+        let mut machine = Machine::new(memory, header);
+        machine.seed(0);
+        *machine.io() = ZIO::headless(commands);
+        let result = machine.step(None);
+        let mut output = machine.io().take_output();
+        if let StepResult::Error(e) = result {
+            output += &format!("{}\n", e);
+        }
+        let _ = send.send(output);
+    });
+    receive.recv_timeout(TIMEOUT).map_err(|e| match e {
+        mpsc::RecvTimeoutError::Timeout => {
+            format!("no result after {} seconds", TIMEOUT.as_secs())
+        }
+        mpsc::RecvTimeoutError::Disconnected => "the interpreter panicked".to_string(),
+    })
+}
+
+// Notes a skipped test. Writing to stderr directly rather than with eprintln keeps the note out of
+// the output the test harness captures, so it shows without --nocapture.
+fn skip(message: &str) {
+    let _ = writeln!(io::stderr(), "{}", message);
+}
+
+fn run(suite: Suite) {
+    let (path, named) = match env::var(suite.var) {
+        Ok(path) => (path, true),
+        Err(_) => (format!("tests/stories/{}", suite.file), false),
+    };
+    let story = match fs::read(&path) {
+        Ok(x) => x,
+        Err(ref e) if !named && e.kind() == io::ErrorKind::NotFound => {
+            skip(&format!(
+                "skipping {}, no {} (see tests/stories/README.md)",
+                suite.name, path
+            ));
+            return;
+        }
+        Err(e) => panic!("can't read {} story {}: {}", suite.name, path, e),
+    };
+    // The header alone is 64 bytes.
+    if story.len() < 64 {
+        panic!(
+            "{} story {} is too short to be a story file, at {} bytes",
+            suite.name,
+            path,
+            story.len()
+        );
+    }
+    if story[0] > SUPPORTED_VERSION {
+        skip(&format!(
+            "skipping {}, {} is a version {} story",
+            suite.name, path, story[0]
+        ));
+        return;
+    }
+    let output = play(story, suite.commands).unwrap_or_else(|e| panic!("{}: {}", suite.name, e));
+    if let Err(e) = (suite.check)(&output) {
+        let tail: Vec<_> = output.lines().rev().take(40).collect();
+        let tail: Vec<_> = tail.into_iter().rev().collect();
+        panic!(
+            "{} failed: {}\n--- last lines of output ---\n{}",
+            suite.name,
+            e,
+            tail.join("\n")
+        );
+    }
+}
+
+#[test]
+fn czech() {
+    run(Suite {
+        name: "CZECH",
+        var: "RUSTZORK_CZECH",
+        file: "czech.z3",
+        commands: &[],
+        check: check_czech,
+    });
+}
+
+#[test]
+fn praxix() {
+    run(Suite {
+        name: "Praxix",
+        var: "RUSTZORK_PRAXIX",
+        file: "praxix.z5",
+        commands: &["all"],
+        check: check_praxix,
+    });
+}
+
+#[test]
+fn etude() {
+    run(Suite {
+        name: "TerpEtude",
+        var: "RUSTZORK_ETUDE",
+        file: "etude.z5",
+        commands: &[],
+        check: check_etude,
+    });
+}
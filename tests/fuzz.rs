@@ -0,0 +1,246 @@
+// Fuzz targets for the decoders, the machine and save files, runnable offline without cargo-fuzz. Each
+// target is fed its corpus and then random mutations of it, and fails on the first input that
+// makes rustzork panic. The corpus is the test stories in tests/stories (built first if they are
+// sources) and any files in tests/fuzz/TARGET.
+//
+// A crashing input is written to target/fuzz/TARGET. Copy it to tests/fuzz/TARGET to reproduce
+// the crash, and keep it there once it's fixed so it stays fixed.
+//
+// RUSTZORK_FUZZ_ITERATIONS sets the number of mutated inputs per target (200 by default) and
+// RUSTZORK_FUZZ_SEED the seed they are generated from (0 by default). Longer runs are best done
+// in release mode:
+//
+//     RUSTZORK_FUZZ_ITERATIONS=100000 cargo test --release --test fuzz
+
+extern crate rand;
+extern crate rustzork;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use rustzork::{assemble, compile, Dictionary, Header, Instruction, Machine, Memory, Routine};
+use rustzork::{Protection, ZString, ZIO};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::panic;
+use std::path::Path;
+use std::sync::OnceLock;
+
+const ITERATIONS: usize = 200;
+const MAX_INSTRUCTIONS: u64 = 10_000;
+const MAX_LENGTH: usize = 0x20000;
+const COMMANDS: &[&str] = &["look", "take all", "north", "open the box", ""];
+
+fn env_number(var: &str, default: u64) -> u64 {
+    match env::var(var) {
+        Ok(x) => x
+            .parse()
+            .unwrap_or_else(|_| panic!("{} should be a number", var)),
+        Err(_) => default,
+    }
+}
+
+fn corpus(target: &str) -> Vec<Vec<u8>> {
+    let mut corpus = Vec::new();
+    let mut paths: Vec<_> = fs::read_dir("tests/stories")
+        .expect("can't read tests/stories")
+        .map(|x| x.unwrap().path())
+        .collect();
+    if let Ok(dir) = fs::read_dir(Path::new("tests/fuzz").join(target)) {
+        paths.extend(dir.map(|x| x.unwrap().path()));
+    }
+    paths.sort();
+    for path in paths {
+        let story = match path.extension().and_then(|x| x.to_str()) {
+            Some("zasm") => assemble(&fs::read_to_string(&path).unwrap()),
+            Some("zil") => compile(&fs::read_to_string(&path).unwrap()).and_then(|x| assemble(&x)),
+            Some("md") => continue,
+            _ => Ok(fs::read(&path).unwrap()),
+        };
+        corpus.push(story.unwrap_or_else(|e| panic!("can't build {}: {}", path.display(), e)));
+    }
+    corpus
+}
+
+// Values likely to hit edge cases: limits of bytes and words, and addresses near the end.
+fn interesting(rng: &mut SmallRng, len: usize) -> u16 {
+    let values = [0, 1, 0x7f, 0x80, 0xff, 0x7fff, 0x8000, 0xffff, len as u16];
+    values[rng.gen_range(0, values.len())]
+}
+
+fn mutate(rng: &mut SmallRng, input: &[u8], corpus: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = input.to_vec();
+    for _ in 0..rng.gen_range(1, 5) {
+        if data.is_empty() {
+            data.push(rng.gen());
+            continue;
+        }
+        let at = rng.gen_range(0, data.len());
+        match rng.gen_range(0, 7) {
+            0 => data[at] ^= 1 << rng.gen_range(0, 8),
+            1 => data[at] = rng.gen(),
+            2 => {
+                let x = interesting(rng, data.len());
+                data[at] = (x >> 8) as u8;
+                if at + 1 < data.len() {
+                    data[at + 1] = x as u8;
+                }
+            }
+            3 => {
+                let bytes: Vec<u8> = (0..rng.gen_range(1, 16)).map(|_| rng.gen()).collect();
+                data.splice(at..at, bytes);
+            }
+            4 => {
+                let end = (at + rng.gen_range(1, 16)).min(data.len());
+                data.drain(at..end);
+            }
+            5 => data.truncate(at),
+            _ => {
+                let other = &corpus[rng.gen_range(0, corpus.len())];
+                if !other.is_empty() {
+                    let from = rng.gen_range(0, other.len());
+                    let end = (from + rng.gen_range(1, 64)).min(other.len());
+                    let end_at = (at + end - from).min(data.len());
+                    data.splice(at..end_at, other[from..end].iter().cloned());
+                }
+            }
+        }
+    }
+    data.truncate(MAX_LENGTH);
+    data
+}
+
+fn message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(x) = payload.downcast_ref::<&str>() {
+        x.to_string()
+    } else if let Some(x) = payload.downcast_ref::<String>() {
+        x.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn fuzz(target: &str, run: fn(&[u8])) {
+    let corpus = corpus(target);
+    let iterations = env_number("RUSTZORK_FUZZ_ITERATIONS", ITERATIONS as u64) as usize;
+    let mut rng = SmallRng::seed_from_u64(env_number("RUSTZORK_FUZZ_SEED", 0));
+    let mutations = (0..iterations).map(|_| {
+        let input = &corpus[rng.gen_range(0, corpus.len())];
+        mutate(&mut rng, input, &corpus)
+    });
+    for data in corpus.clone().into_iter().chain(mutations) {
+        if let Err(e) = panic::catch_unwind(|| run(&data)) {
+            let mut hasher = DefaultHasher::new();
+            data.hash(&mut hasher);
+            let dir = Path::new("target/fuzz").join(target);
+            let path = dir.join(format!("crash-{:016x}", hasher.finish()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(&path, &data).unwrap();
+            panic!(
+                "{} target panicked ({}), input saved to {}",
+                target,
+                message(&*e),
+                path.display()
+            );
+        }
+    }
+}
+
+// Decodes the routines found in high memory and the instructions from the initial PC on.
+fn instruction(data: &[u8]) {
+    let memory = Memory::new(data);
+    let header = Header::new(&memory);
+    for routine in Routine::find_all(&memory, &header) {
+        for i in routine.instructions() {
+            let _ = format!("{}", i);
+        }
+    }
+    let mut pc = memory.read_u16(0x6) as usize;
+    for _ in 0..64 {
+        if pc >= data.len() {
+            break;
+        }
+        let i = Instruction::new(&memory, pc);
+        let _ = format!("{}", i);
+        i.branch_target();
+        pc += i.length();
+    }
+}
+
+// Decodes strings at up to 256 evenly spread addresses.
+fn zstring(data: &[u8]) {
+    let memory = Memory::new(data);
+    let stride = (data.len() / 256 + 1) & !1;
+    for offset in (0..data.len()).step_by(stride.max(2)) {
+        let _ = format!("{}", ZString::new(&memory, offset));
+        let _ = format!("{}", ZString::with_max_length(&memory, offset, 4));
+    }
+}
+
+fn dictionary(data: &[u8]) {
+    let memory = Memory::new(data);
+    Header::new(&memory);
+    let dictionary = Dictionary::new(&memory, memory.read_u16(0x8) as usize);
+    for word in &["look", "a", "northeast", ""] {
+        dictionary.get_word(word);
+    }
+}
+
+fn machine(data: &[u8]) {
+    let memory = Memory::new(data);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    machine.seed(0);
+    machine.protect(match data.len() % 3 {
+        0 => Protection::Strict,
+        1 => Protection::Warn,
+        _ => Protection::Permissive,
+    });
+    *machine.io() = ZIO::headless(COMMANDS);
+    machine.step(Some(MAX_INSTRUCTIONS));
+    machine.backtrace();
+}
+
+// Restores saves into the ZIL test game and runs it from there.
+fn quetzal(data: &[u8]) {
+    static STORY: OnceLock<Vec<u8>> = OnceLock::new();
+    let story = STORY.get_or_init(|| {
+        let source = fs::read_to_string("tests/stories/lantern.zil").unwrap();
+        assemble(&compile(&source).unwrap()).unwrap()
+    });
+    let memory = Memory::new(story);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    machine.seed(0);
+    *machine.io() = ZIO::headless(COMMANDS);
+    if machine.restore_game(data).is_ok() {
+        machine.step(Some(MAX_INSTRUCTIONS));
+        machine.backtrace();
+    }
+}
+
+#[test]
+fn fuzz_instruction() {
+    fuzz("instruction", instruction);
+}
+
+#[test]
+fn fuzz_zstring() {
+    fuzz("zstring", zstring);
+}
+
+#[test]
+fn fuzz_dictionary() {
+    fuzz("dictionary", dictionary);
+}
+
+#[test]
+fn fuzz_machine() {
+    fuzz("machine", machine);
+}
+
+#[test]
+fn fuzz_quetzal() {
+    fuzz("quetzal", quetzal);
+}
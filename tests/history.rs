@@ -0,0 +1,130 @@
+// Tests of running the machine backwards through its recorded history, using small assembled
+// stories.
+//
+//     cargo test --test history
+
+extern crate rustzork;
+
+use rustzork::{assemble, Header, Machine, Memory, StepResult, ZIO};
+
+fn machine(source: &str) -> Machine {
+    let story = assemble(source).unwrap();
+    let memory = Memory::new(&story);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    *machine.io() = ZIO::headless::<&str>(&[]);
+    machine.seed(0);
+    machine.record_history(1000);
+    machine
+}
+
+const COUNT: &str = "
+.global count 0
+.global total 0
+
+.routine main
+    CALL add #03 -> -(SP)
+    CALL add #04 -> total
+    INC [count]
+    PRINT_NUM total
+    NEW_LINE
+    QUIT
+
+.routine add 2
+    INC [count]
+    ADD L00,#10 -> L01
+    PUSH L01
+    RET_POPPED
+";
+
+const DICE: &str = "
+.routine main
+    RANDOM #64 -> -(SP)
+    PRINT_NUM (SP)+
+    NEW_LINE
+    RANDOM #64 -> -(SP)
+    PRINT_NUM (SP)+
+    NEW_LINE
+    QUIT
+";
+
+#[test]
+fn step_back_to_the_start() {
+    let mut machine = machine(COUNT);
+    let start = machine.snapshot();
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.io().take_output(), "20\n");
+    let count = machine.instruction_count() as usize;
+    assert_eq!(machine.history_len(), count);
+    for _ in 0..count {
+        assert!(machine.step_back());
+    }
+    assert!(!machine.step_back());
+    assert_eq!(machine.instruction_count(), 0);
+    assert_eq!(
+        machine.compare(&start, &machine.snapshot()).unwrap(),
+        "The games are the same.\n"
+    );
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.io().take_output(), "20\n");
+}
+
+#[test]
+fn step_back_inside_a_routine() {
+    let mut machine = machine(COUNT);
+    assert_eq!(machine.step(Some(3)), StepResult::Yielded);
+    let inside = machine.backtrace();
+    assert_eq!(inside.frames.len(), 2);
+    assert_eq!(machine.step(Some(4)), StepResult::Yielded);
+    for _ in 0..4 {
+        machine.step_back();
+    }
+    assert_eq!(machine.backtrace(), inside);
+}
+
+#[test]
+fn run_back_to_write() {
+    let mut machine = machine(COUNT);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    // The first global, count, is the first word of the globals table.
+    let globals = machine.snapshot();
+    let count = Memory::new(&assemble(COUNT).unwrap()).read_u16(0xc) as usize;
+    assert_eq!(machine.run_back_to_write(count, 2), Some(4));
+    assert_eq!(machine.backtrace().frames.len(), 1);
+    assert!(machine
+        .compare(&machine.snapshot(), &globals)
+        .unwrap()
+        .contains("G00: 0002 -> 0003"));
+    assert_eq!(machine.run_back_to_write(count, 2), Some(4));
+    assert_eq!(machine.backtrace().frames.len(), 2);
+    assert_eq!(machine.run_back_to_write(count + 4, 2), None);
+}
+
+#[test]
+fn random_numbers_replay() {
+    let mut machine = machine(DICE);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    let first = machine.io().take_output();
+    while machine.step_back() {}
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.io().take_output(), first);
+}
+
+#[test]
+fn history_is_limited() {
+    let mut machine = machine(COUNT);
+    machine.record_history(5);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.history_len(), 5);
+    machine.restart();
+    assert_eq!(machine.history_len(), 0);
+}
+
+#[test]
+fn history_off() {
+    let mut machine = machine(COUNT);
+    machine.record_history(0);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.history_len(), 0);
+    assert!(!machine.step_back());
+}
@@ -0,0 +1,87 @@
+// Tests of reading and writing object table fields in place, on a small object table built by
+// hand.
+//
+//     cargo test --test object
+
+extern crate rustzork;
+
+use rustzork::{Memory, Object};
+
+// Three objects after the property defaults at 0x40. Object 1 has property 5 of two bytes and
+// property 2 of one; the others have no properties. None of them has a short name.
+fn memory() -> Memory {
+    let mut story = vec![0; 0x100];
+    story[0] = 3;
+    story[0xb] = 0x40;
+    // All of it is dynamic memory.
+    story[0xe] = 0x01;
+    let tables = [0xa0, 0xb0, 0xc0];
+    for (n, &table) in tables.iter().enumerate() {
+        story[0x7e + n * 9 + 8] = table;
+    }
+    story[0xa0..0xa7].copy_from_slice(&[0x00, 0x25, 0xbe, 0xef, 0x02, 0x07, 0x00]);
+    Memory::new(&story)
+}
+
+#[test]
+fn attributes() {
+    let mut memory = memory();
+    let lamp = Object::new(&memory, 2);
+    for &attr in &[0, 9, 31] {
+        assert!(!lamp.test_attr(&memory, attr));
+        lamp.set_attr(&mut memory, attr, true);
+        assert!(lamp.test_attr(&memory, attr));
+    }
+    assert_eq!(memory.read_u16(0x87), 0x8040);
+    assert_eq!(memory.read_u16(0x89), 0x0001);
+    lamp.set_attr(&mut memory, 9, false);
+    assert!(!lamp.test_attr(&memory, 9));
+    assert!(lamp.test_attr(&memory, 0));
+    assert_eq!(memory.read_u16(0x87), 0x8000);
+}
+
+#[test]
+fn tree() {
+    let mut memory = memory();
+    let room = Object::new(&memory, 1);
+    let lamp = Object::new(&memory, 2);
+    let key = Object::new(&memory, 3);
+    lamp.insert(&mut memory, &room);
+    key.insert(&mut memory, &room);
+    assert_eq!(room.child(&memory), 3);
+    assert_eq!(key.sibling(&memory), 2);
+    assert_eq!(lamp.sibling(&memory), 0);
+    assert_eq!(lamp.parent(&memory), 1);
+    assert_eq!(memory.read_u8(0x87 + 4), 1);
+
+    key.remove(&mut memory);
+    assert_eq!(room.child(&memory), 2);
+    assert_eq!(key.parent(&memory), 0);
+    assert_eq!(key.sibling(&memory), 0);
+
+    key.insert(&mut memory, &lamp);
+    assert_eq!(lamp.child(&memory), 3);
+    assert_eq!(key.parent(&memory), 2);
+}
+
+#[test]
+fn properties() {
+    let mut memory = memory();
+    let room = Object::new(&memory, 1);
+    assert_eq!(room.get_property(&memory, 5).read(&memory), 0xbeef);
+    assert_eq!(room.get_property(&memory, 2).read(&memory), 0x07);
+    assert!(room.get_property_opt(&memory, 4).is_none());
+
+    room.get_property(&memory, 5).write(&mut memory, 0x1234);
+    room.get_property(&memory, 2).write(&mut memory, 0x1234);
+    assert_eq!(memory.read_u16(0xa2), 0x1234);
+    assert_eq!(memory.read_u8(0xa5), 0x34);
+    assert_eq!(room.get_property(&memory, 2).read(&memory), 0x34);
+
+    assert_eq!(room.get_next_property(&memory, 0), Some(5));
+    assert_eq!(room.get_next_property(&memory, 5), Some(2));
+    assert_eq!(room.get_next_property(&memory, 2), Some(0));
+    assert_eq!(room.get_next_property(&memory, 4), None);
+    let key = Object::new(&memory, 3);
+    assert_eq!(key.get_next_property(&memory, 0), Some(0));
+}
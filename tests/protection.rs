@@ -0,0 +1,100 @@
+// Tests of how the machine treats a game writing outside dynamic memory, using small assembled
+// stories.
+//
+//     cargo test --test protection
+
+extern crate rustzork;
+
+use rustzork::{assemble, Header, Machine, Memory, Protection, StepResult, ZIO};
+
+fn machine(source: &str, protection: Protection) -> Machine {
+    let story = assemble(source).unwrap();
+    let memory = Memory::new(&story);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    *machine.io() = ZIO::headless::<&str>(&[]);
+    machine.protect(protection);
+    machine
+}
+
+// Calls a routine, then patches its RTRUE into an RFALSE in high memory and calls it again.
+const PATCH: &str = "
+.routine main
+    CALL check -> -(SP)
+    PRINT_NUM (SP)+
+    NEW_LINE
+    MUL check,#02 -> -(SP)
+    STOREB (SP)+,#01,#b1
+    CALL check -> -(SP)
+    PRINT_NUM (SP)+
+    NEW_LINE
+    QUIT
+
+.routine check
+    RTRUE
+";
+
+#[test]
+fn patched_code_runs() {
+    for protection in [Protection::Warn, Protection::Permissive] {
+        let mut machine = machine(PATCH, protection);
+        assert_eq!(machine.step(None), StepResult::Quit);
+        let output = machine.io().take_output();
+        let numbers: Vec<_> = output
+            .lines()
+            .filter(|x| !x.starts_with("Warning"))
+            .collect();
+        assert_eq!(numbers, ["1", "0"], "{:?}", protection);
+    }
+}
+
+#[test]
+fn patched_code_is_refused() {
+    let mut machine = machine(PATCH, Protection::Strict);
+    match machine.step(None) {
+        StepResult::Error(fault) => assert!(fault.message.contains("outside dynamic memory")),
+        x => panic!("expected an error, got {:?}", x),
+    }
+    assert_eq!(machine.io().take_output(), "1\n");
+}
+
+// Object 200 would be past the end of this story.
+const OBJECTS: &str = "
+.object lamp \"lamp\"
+
+.routine main
+    SET_ATTR lamp,#01
+    PRINT \"set\"
+    NEW_LINE
+    INSERT_OBJ #c8,lamp
+    SET_ATTR #c8,#01
+    PRINT \"wild\"
+    NEW_LINE
+    QUIT
+";
+
+#[test]
+fn object_writes_are_refused() {
+    let mut machine = machine(OBJECTS, Protection::Strict);
+    match machine.step(None) {
+        StepResult::Error(fault) => assert!(fault.message.contains("past the end of memory")),
+        x => panic!("expected an error, got {:?}", x),
+    }
+    assert_eq!(machine.io().take_output(), "set\n");
+}
+
+#[test]
+fn object_writes_warn() {
+    let mut machine = machine(OBJECTS, Protection::Warn);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    let output = machine.io().take_output();
+    assert!(output.contains("past the end of memory"), "{:?}", output);
+    assert!(output.ends_with("wild\n"), "{:?}", output);
+}
+
+#[test]
+fn object_writes_go_ahead() {
+    let mut machine = machine(OBJECTS, Protection::Permissive);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.io().take_output(), "set\nwild\n");
+}
@@ -0,0 +1,273 @@
+// Tests of taking snapshots of a running game, saving it and cloning it, using the ZIL test game.
+//
+//     cargo test --test snapshot
+
+extern crate rustzork;
+
+use rustzork::{assemble, compile, Header, Machine, Memory, StepResult, ZIO};
+use std::fs;
+
+const DICE: &str = "
+.routine main
+    CALL roll -> -(SP)
+    QUIT
+
+.routine roll 1
+    RANDOM #64 -> L00
+    PRINT_NUM L00
+    NEW_LINE
+    RANDOM #64 -> L00
+    PRINT_NUM L00
+    NEW_LINE
+    RTRUE
+";
+
+fn load(story: &[u8]) -> Machine {
+    let memory = Memory::new(story);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    machine.seed(0);
+    machine
+}
+
+fn story() -> Vec<u8> {
+    let source = fs::read_to_string("tests/stories/lantern.zil").unwrap();
+    assemble(&compile(&source).unwrap()).unwrap()
+}
+
+fn lantern() -> Machine {
+    let mut machine = load(&story());
+    *machine.io() = ZIO::headless::<&str>(&[]);
+    assert!(matches!(machine.step(None), StepResult::NeedsLine { .. }));
+    machine.io().take_output();
+    machine
+}
+
+// Plays the commands and returns what the game printed.
+fn play(machine: &mut Machine, commands: &[&str]) -> String {
+    *machine.io() = ZIO::headless(commands);
+    assert!(matches!(machine.step(None), StepResult::NeedsLine { .. }));
+    machine.io().take_output()
+}
+
+#[test]
+fn restore_rewinds() {
+    let mut machine = lantern();
+    let snapshot = machine.snapshot();
+    let count = machine.instruction_count();
+    assert!(play(&mut machine, &["take lantern"]).contains("Taken."));
+    machine.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(machine.instruction_count(), count);
+    assert!(play(&mut machine, &["inventory"]).contains("You are empty-handed."));
+    assert!(play(&mut machine, &["take lantern"]).contains("Taken."));
+}
+
+#[test]
+fn restore_after_quit() {
+    let mut machine = lantern();
+    let snapshot = machine.snapshot();
+    *machine.io() = ZIO::headless(&["quit"]);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    machine.restore_snapshot(&snapshot).unwrap();
+    assert!(play(&mut machine, &["look"]).contains("A weedy yard."));
+}
+
+#[test]
+fn snapshots_are_small() {
+    assert_eq!(load(&story()).snapshot().size(), 0);
+    let mut machine = lantern();
+    play(
+        &mut machine,
+        &["take lantern", "down", "open box", "take key"],
+    );
+    let snapshot = machine.snapshot();
+    assert!(snapshot.size() < 128, "{} bytes", snapshot.size());
+}
+
+#[test]
+fn restore_keeps_random_numbers() {
+    let mut machine = load(&assemble(DICE).unwrap());
+    *machine.io() = ZIO::headless::<&str>(&[]);
+    let snapshot = machine.snapshot();
+    assert_eq!(machine.step(None), StepResult::Quit);
+    let first = machine.io().take_output();
+    machine.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.io().take_output(), first);
+}
+
+#[test]
+fn restore_other_story() {
+    let mut machine = lantern();
+    play(&mut machine, &["take lantern", "down", "open box"]);
+    let snapshot = machine.snapshot();
+    let mut other = load(&assemble(DICE).unwrap());
+    assert!(other.restore_snapshot(&snapshot).is_err());
+    *other.io() = ZIO::headless::<&str>(&[]);
+    assert_eq!(other.step(None), StepResult::Quit);
+}
+
+#[test]
+fn restores_keep_to_the_undo_depth() {
+    let mut machine = lantern();
+    machine.keep_undo(1);
+    let mut io = ZIO::headless(&["/save start", "/restore start", "/restore start", "/undo 2"]);
+    io.save_dir("target/snapshot/undo");
+    *machine.io() = io;
+    machine.step(None);
+    let output = machine.io().take_output();
+    assert!(output.contains("[Previous turn undone.]"), "{}", output);
+}
+
+#[test]
+fn clones_are_independent() {
+    let mut machine = lantern();
+    let mut clone = machine.clone();
+    assert!(play(&mut clone, &["take lantern"]).contains("Taken."));
+    assert!(play(&mut machine, &["inventory"]).contains("You are empty-handed."));
+    assert!(play(&mut clone, &["inventory"]).contains("brass lantern"));
+}
+
+#[test]
+fn save_restores_in_new_machine() {
+    let mut machine = lantern();
+    play(&mut machine, &["take lamp", "light lamp", "down"]);
+    let save = machine.save_game();
+    assert_eq!(&save[..4], b"FORM");
+    assert_eq!(&save[8..12], b"IFZS");
+
+    let mut other = lantern();
+    other.restore_game(&save).unwrap();
+    assert_eq!(other.backtrace(), machine.backtrace());
+    assert!(play(&mut other, &["look"]).contains("A damp cellar."));
+    assert!(play(&mut other, &["up"]).contains("A weedy yard."));
+}
+
+#[test]
+fn restore_checks_story() {
+    let save = lantern().save_game();
+    let mut machine = load(&assemble(DICE).unwrap());
+    assert_eq!(
+        machine.restore_game(&save),
+        Err("the save is of a different story".to_string())
+    );
+    assert_eq!(
+        machine.restore_game(b"FORM\0\0\0\x04IFZS"),
+        Err("the save has no IFhd chunk".to_string())
+    );
+}
+
+// Other interpreters save with the PC in a save instruction, so one pointing anywhere but at a
+// read isn't rustzork's.
+#[test]
+fn restore_refuses_other_pcs() {
+    let mut save = lantern().save_game();
+    assert_eq!(&save[12..16], b"IFhd");
+    save[32] += 1;
+    assert_eq!(
+        lantern().restore_game(&save),
+        Err("the save wasn't made by rustzork, which saves at a read instruction".to_string())
+    );
+    assert!(lantern().save_info(&save).is_err());
+}
+
+#[test]
+fn autosave_at_each_read() {
+    let path = "target/autosave.qzl";
+    let _ = fs::remove_file(path);
+    let mut machine = lantern();
+    machine.autosave(Some(path.to_string()));
+    play(&mut machine, &["take lamp", "light lamp"]);
+    let mut other = lantern();
+    other.restore_game(&fs::read(path).unwrap()).unwrap();
+    assert_eq!(other.backtrace(), machine.backtrace());
+    assert!(play(&mut other, &["i"]).contains("brass lantern"));
+}
+
+#[test]
+fn autosave_forgotten_on_quit() {
+    let path = "target/quit.qzl";
+    let mut machine = lantern();
+    machine.autosave(Some(path.to_string()));
+    play(&mut machine, &["take lamp"]);
+    assert!(fs::metadata(path).is_ok());
+    *machine.io() = ZIO::headless(&["/quit"]);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert!(fs::metadata(path).is_err());
+}
+
+#[test]
+fn save_info_shows_changes() {
+    let mut machine = lantern();
+    play(&mut machine, &["take lamp", "light lamp", "down"]);
+    let info = lantern().save_info(&machine.save_game()).unwrap();
+    assert!(
+        info.contains("Backtrace (most recent call first):"),
+        "{}",
+        info
+    );
+    assert!(
+        info.contains("parent: 3 \"Yard\" -> 4 \"Cellar\""),
+        "{}",
+        info
+    );
+    assert!(info.contains("parent: 3 \"Yard\" -> 2 \"you\""), "{}", info);
+
+    let save = load(&assemble(DICE).unwrap()).save_game();
+    let error = lantern().save_info(&save).unwrap_err();
+    assert!(error.starts_with("the save is of release"), "{}", error);
+}
+
+#[test]
+fn convert_keeps_the_game() {
+    let mut machine = lantern();
+    play(&mut machine, &["take lamp", "light lamp", "down"]);
+    let save = machine.save_game();
+    let whole = machine.convert_save(&save, false).unwrap();
+    assert!(whole.windows(4).any(|x| x == b"UMem"));
+    assert!(!whole.windows(4).any(|x| x == b"CMem"));
+    assert_eq!(machine.convert_save(&whole, true).unwrap(), save);
+
+    let mut other = lantern();
+    other.restore_game(&whole).unwrap();
+    assert_eq!(other.backtrace(), machine.backtrace());
+    assert!(play(&mut other, &["up"]).contains("A weedy yard."));
+}
+
+#[test]
+fn compare_in_game_terms() {
+    let mut machine = lantern();
+    let before = machine.snapshot();
+    play(&mut machine, &["take lamp", "light lamp"]);
+    let after = machine.snapshot();
+    let report = machine.compare(&before, &after).unwrap();
+    assert!(
+        report.contains("parent: 3 \"Yard\" -> 2 \"you\""),
+        "{}",
+        report
+    );
+    assert!(report.contains("attributes: +4"), "{}", report);
+    assert!(report.contains("G02: 0000 -> 0002"), "{}", report);
+    assert!(!report.contains("Call stack:"), "{}", report);
+    assert_eq!(
+        machine.compare(&after, &after).unwrap(),
+        "The games are the same.\n"
+    );
+
+    let start = load(&story()).snapshot();
+    let report = machine.compare(&start, &after).unwrap();
+    assert!(report.contains("Call stack:\n  - #0 main"), "{}", report);
+    assert!(report.contains("  + #1 routine"), "{}", report);
+}
+
+#[test]
+fn compare_saves() {
+    let mut machine = lantern();
+    let first = machine.save_game();
+    play(&mut machine, &["take lamp"]);
+    let report = lantern()
+        .compare_saves(&first, &machine.save_game())
+        .unwrap();
+    assert!(report.contains("7 \"brass lantern\""), "{}", report);
+    assert!(lantern().compare_saves(&first, b"junk").is_err());
+}
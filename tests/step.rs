@@ -0,0 +1,132 @@
+// Tests of how Machine::step hands control back to the host, using small assembled stories.
+//
+//     cargo test --test step
+
+extern crate rustzork;
+
+use rustzork::{assemble, Header, Machine, Memory, StepResult, ZIO};
+
+fn machine(source: &str, commands: &[&str]) -> Machine {
+    let story = assemble(source).unwrap();
+    let memory = Memory::new(&story);
+    let header = Header::new(&memory);
+    let mut machine = Machine::new(memory, header);
+    *machine.io() = ZIO::headless(commands);
+    machine
+}
+
+const LOOP: &str = "
+.routine main
+loop:
+    PUSH #00
+    POP
+    JUMP loop
+";
+
+const READ: &str = "
+.table textbuf #28
+.space 41
+.table parsebuf #05
+.space 21
+
+.routine main
+    SREAD textbuf,parsebuf
+    PRINT \"read\"
+    NEW_LINE
+    QUIT
+";
+
+const UNDERFLOW: &str = "
+.routine main
+    PRINT \"oops\"
+    POP
+    QUIT
+";
+
+const RESTART: &str = "
+.global runs 0
+
+.routine main
+    INC [runs]
+    PRINT_NUM runs
+    NEW_LINE
+    RESTART
+";
+
+const HELLO: &str = "
+.routine main
+    PRINT \"hello\"
+    NEW_LINE
+    QUIT
+";
+
+#[test]
+fn budget_yields() {
+    let mut machine = machine(LOOP, &[]);
+    assert_eq!(machine.step(Some(1000)), StepResult::Yielded);
+    assert_eq!(machine.instruction_count(), 1000);
+    assert_eq!(machine.step(Some(500)), StepResult::Yielded);
+    assert_eq!(machine.instruction_count(), 1500);
+}
+
+#[test]
+fn budget_left_over() {
+    let mut machine = machine(HELLO, &[]);
+    assert_eq!(machine.step(Some(1000)), StepResult::Quit);
+    assert_eq!(machine.instruction_count(), 3);
+    assert_eq!(machine.io().take_output(), "hello\n");
+}
+
+#[test]
+fn yield_resumes() {
+    let mut machine = machine(HELLO, &[]);
+    assert_eq!(machine.step(Some(1)), StepResult::Yielded);
+    assert_eq!(machine.step(Some(1)), StepResult::Yielded);
+    assert_eq!(machine.step(Some(1)), StepResult::Quit);
+    assert_eq!(machine.io().take_output(), "hello\n");
+}
+
+#[test]
+fn needs_line() {
+    let mut machine = machine(READ, &[]);
+    assert_eq!(machine.step(None), StepResult::NeedsLine { max_length: 40 });
+    assert_eq!(machine.step(None), StepResult::NeedsLine { max_length: 40 });
+    assert_eq!(machine.io().take_output(), "");
+}
+
+#[test]
+fn quit_is_final() {
+    let mut machine = machine(HELLO, &[]);
+    assert_eq!(machine.step(None), StepResult::Quit);
+    let count = machine.instruction_count();
+    assert_eq!(machine.step(None), StepResult::Quit);
+    assert_eq!(machine.instruction_count(), count);
+}
+
+#[test]
+fn error_has_fault() {
+    let mut machine = machine(UNDERFLOW, &[]);
+    match machine.step(None) {
+        StepResult::Error(fault) => {
+            assert_eq!(fault.message, "stack underflow");
+            // The POP, after the five bytes of the PRINT.
+            let story = assemble(UNDERFLOW).unwrap();
+            let main = ((story[6] as usize) << 8) | story[7] as usize;
+            assert_eq!(fault.pc, main + 5);
+            assert_eq!(fault.backtrace, machine.backtrace());
+            assert!(fault.to_string().starts_with("stack underflow\n\n"));
+        }
+        x => panic!("expected an error, got {:?}", x),
+    }
+    assert_eq!(machine.io().take_output(), "oops");
+    assert!(matches!(machine.step(None), StepResult::Error(_)));
+}
+
+#[test]
+fn restart_reloads() {
+    let mut machine = machine(RESTART, &[]);
+    assert_eq!(machine.step(None), StepResult::Restart);
+    machine.restart();
+    assert_eq!(machine.step(None), StepResult::Restart);
+    assert_eq!(machine.io().take_output(), "1\n1\n");
+}
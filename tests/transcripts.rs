@@ -0,0 +1,219 @@
+// Golden-transcript tests. Each tests/transcripts/NAME.cmds file names a story and an RNG seed
+// in its header and lists the commands to play, one per line:
+//
+//     # story: tests/stories/objects.zasm
+//     # seed: 0
+//     look
+//
+// Stories with a .zasm extension are assembled first (see src/assembler.rs), and .zil ones
+// compiled and then assembled (see src/zil.rs). The game is run headlessly, keeping its named
+// saves in target/transcripts/NAME, and its whole output compared with NAME.txt next to it. Set
+// RUSTZORK_BLESS to write the current output as the expected transcript instead. Cases whose
+// story file is missing are skipped.
+//
+//     cargo test --test transcripts
+
+extern crate rustzork;
+
+use rustzork::{assemble, compile, Header, Machine, Memory, StepResult, ZIO};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CONTEXT: usize = 3;
+
+struct Case {
+    story: PathBuf,
+    seed: u64,
+    commands: Vec<String>,
+}
+
+impl Case {
+    fn parse(path: &Path) -> Result<Case, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut story = None;
+        let mut seed = 0;
+        let mut commands = Vec::new();
+        for line in text.lines() {
+            if let Some(header) = line.strip_prefix('#') {
+                match header.trim().split_once(':') {
+                    Some(("story", x)) => story = Some(PathBuf::from(x.trim())),
+                    Some(("seed", x)) => {
+                        seed = x.trim().parse().map_err(|_| format!("bad seed {:?}", x))?
+                    }
+                    _ => return Err(format!("unknown header {:?}", line)),
+                }
+            } else {
+                commands.push(line.to_string());
+            }
+        }
+        Ok(Case {
+            story: story.ok_or("no story")?,
+            seed,
+            commands,
+        })
+    }
+
+    fn play(&self, story: &[u8], saves: &Path) -> String {
+        let memory = Memory::new(story);
+        let header = Header::new(&memory);
+        let mut machine = Machine::new(memory, header);
+        machine.seed(self.seed);
+        *machine.io() = ZIO::headless(&self.commands);
+        let _ = fs::remove_dir_all(saves);
+        machine.io().save_dir(&saves.to_string_lossy());
+        let mut output = String::new();
+        loop {
+            let result = machine.step(None);
+            output += &machine.io().take_output();
+            match result {
+                StepResult::Restart => machine.restart(),
+                StepResult::Error(e) => {
+                    output += &format!("{}\n", e);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        output
+    }
+}
+
+// Line diff of expected against actual, as hunks of removed (-) and added (+) lines with some
+// context. Lines common to both ends are trimmed before the longest common subsequence is
+// found, so a local change in a long transcript stays cheap.
+fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut lcs = vec![vec![0usize; mb.len() + 1]; ma.len() + 1];
+    for i in (0..ma.len()).rev() {
+        for j in (0..mb.len()).rev() {
+            lcs[i][j] = if ma[i] == mb[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    // Every line of the output as (tag, line number in expected, text).
+    let mut lines: Vec<(char, usize, &str)> = Vec::new();
+    for (n, x) in a[..prefix].iter().enumerate() {
+        lines.push((' ', n, x));
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < ma.len() || j < mb.len() {
+        if i < ma.len() && j < mb.len() && ma[i] == mb[j] {
+            lines.push((' ', prefix + i, ma[i]));
+            i += 1;
+            j += 1;
+        } else if j == mb.len() || (i < ma.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', prefix + i, ma[i]));
+            i += 1;
+        } else {
+            lines.push(('+', prefix + i, mb[j]));
+            j += 1;
+        }
+    }
+    for (n, x) in a[a.len() - suffix..].iter().enumerate() {
+        lines.push((' ', a.len() - suffix + n, x));
+    }
+
+    let mut out = String::new();
+    let mut last = None;
+    for (k, &(tag, n, text)) in lines.iter().enumerate() {
+        let start = k.saturating_sub(CONTEXT);
+        let end = (k + CONTEXT + 1).min(lines.len());
+        if !lines[start..end].iter().any(|x| x.0 != ' ') {
+            continue;
+        }
+        if last.is_none_or(|x| x + 1 != k) {
+            out += &format!("@@ expected line {} @@\n", n + 1);
+        }
+        out += &format!("{} {}\n", tag, text);
+        last = Some(k);
+    }
+    if out.is_empty() {
+        out += "(the transcripts differ only in line endings)\n";
+    }
+    out
+}
+
+#[test]
+fn transcripts() {
+    let bless = env::var_os("RUSTZORK_BLESS").is_some();
+    let mut cases: Vec<PathBuf> = fs::read_dir("tests/transcripts")
+        .expect("can't read tests/transcripts")
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().is_some_and(|x| x == "cmds"))
+        .collect();
+    cases.sort();
+
+    let mut failures = Vec::new();
+    for path in cases {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let case = Case::parse(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let story = match case.story.extension().and_then(|x| x.to_str()) {
+            Some("zasm") => fs::read_to_string(&case.story).map(|x| assemble(&x)),
+            Some("zil") => fs::read_to_string(&case.story).map(|x| {
+                compile(&x).and_then(|x| assemble(&x).map_err(|e| format!("assembly {}", e)))
+            }),
+            _ => fs::read(&case.story).map(Ok),
+        };
+        let story = match story {
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) => {
+                failures.push(format!(
+                    "{}: can't build {}: {}",
+                    name,
+                    case.story.display(),
+                    e
+                ));
+                continue;
+            }
+            Err(e) => {
+                println!(
+                    "skipping {}, can't read {}: {}",
+                    name,
+                    case.story.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        let actual = case.play(&story, &Path::new("target/transcripts").join(&name));
+        let expected_path = path.with_extension("txt");
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            println!("blessed {}", expected_path.display());
+            continue;
+        }
+        match fs::read_to_string(&expected_path) {
+            Ok(ref expected) if *expected == actual => {}
+            Ok(expected) => failures.push(format!(
+                "{}: output differs from {}\n{}",
+                name,
+                expected_path.display(),
+                diff(&expected, &actual)
+            )),
+            Err(e) => failures.push(format!(
+                "{}: can't read {} ({}), run with RUSTZORK_BLESS=1 to create it",
+                name,
+                expected_path.display(),
+                e
+            )),
+        }
+    }
+    if !failures.is_empty() {
+        panic!("\n{}", failures.join("\n"));
+    }
+}